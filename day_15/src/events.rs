@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+
+use crate::CombatRules;
+use crate::Unit::{self, *};
+
+// Everything that happens during a battle, in order.  Units are named by where they stand when
+// the event happens.  `round` counts the rounds that have already finished, like the outcome does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    RoundStarted {
+        round: usize,
+    },
+    UnitMoved {
+        from: (usize, usize),
+        to: (usize, usize),
+    },
+    UnitAttacked {
+        attacker: (usize, usize),
+        target: (usize, usize),
+        // the hit points actually lost, so no more than the target had on a killing blow
+        damage: usize,
+        hp_left: usize,
+    },
+    UnitDied {
+        position: (usize, usize),
+    },
+    CombatEnded {
+        rounds: usize,
+        hp_left: usize,
+        outcome: usize,
+    },
+}
+
+fn position_json((row, col): (usize, usize)) -> String {
+    format!("[{},{}]", row, col)
+}
+
+impl Event {
+    // One line of JSONL.
+    pub fn to_json(self) -> String {
+        match self {
+            Event::RoundStarted { round } => {
+                format!(r#"{{"event":"RoundStarted","round":{}}}"#, round)
+            }
+            Event::UnitMoved { from, to } => format!(
+                r#"{{"event":"UnitMoved","from":{},"to":{}}}"#,
+                position_json(from),
+                position_json(to)
+            ),
+            Event::UnitAttacked {
+                attacker,
+                target,
+                damage,
+                hp_left,
+            } => format!(
+                r#"{{"event":"UnitAttacked","attacker":{},"target":{},"damage":{},"hp_left":{}}}"#,
+                position_json(attacker),
+                position_json(target),
+                damage,
+                hp_left
+            ),
+            Event::UnitDied { position } => format!(
+                r#"{{"event":"UnitDied","position":{}}}"#,
+                position_json(position)
+            ),
+            Event::CombatEnded {
+                rounds,
+                hp_left,
+                outcome,
+            } => format!(
+                r#"{{"event":"CombatEnded","rounds":{},"hp_left":{},"outcome":{}}}"#,
+                rounds, hp_left, outcome
+            ),
+        }
+    }
+
+    // Reads back a line written by to_json.  This only understands the flat objects we write,
+    // not JSON in general.
+    pub fn from_json(line: &str) -> Result<Event, String> {
+        let fields = fields(line)?;
+        let get = |key: &str| {
+            fields
+                .get(key)
+                .cloned()
+                .ok_or_else(|| format!("missing field {:?}", key))
+        };
+        let number = |key: &str| -> Result<usize, String> {
+            get(key)?
+                .parse()
+                .map_err(|_| format!("field {:?} is not a number", key))
+        };
+        let position = |key: &str| -> Result<(usize, usize), String> {
+            let value = get(key)?;
+            let bad = || format!("field {:?} is not a [row,col] pair", key);
+            let inner = value
+                .strip_prefix('[')
+                .and_then(|v| v.strip_suffix(']'))
+                .ok_or_else(bad)?;
+            let mut parts = inner.split(',').map(|part| part.trim().parse());
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(Ok(row)), Some(Ok(col)), None) => Ok((row, col)),
+                _ => Err(bad()),
+            }
+        };
+
+        match get("event")?.trim_matches('"') {
+            "RoundStarted" => Ok(Event::RoundStarted {
+                round: number("round")?,
+            }),
+            "UnitMoved" => Ok(Event::UnitMoved {
+                from: position("from")?,
+                to: position("to")?,
+            }),
+            "UnitAttacked" => Ok(Event::UnitAttacked {
+                attacker: position("attacker")?,
+                target: position("target")?,
+                damage: number("damage")?,
+                hp_left: number("hp_left")?,
+            }),
+            "UnitDied" => Ok(Event::UnitDied {
+                position: position("position")?,
+            }),
+            "CombatEnded" => Ok(Event::CombatEnded {
+                rounds: number("rounds")?,
+                hp_left: number("hp_left")?,
+                outcome: number("outcome")?,
+            }),
+            other => Err(format!("unknown event {}", other)),
+        }
+    }
+}
+
+// Splits `{"a":1,"b":[2,3]}` into its keys and raw values.
+fn fields(line: &str) -> Result<HashMap<&str, &str>, String> {
+    let body = line
+        .trim()
+        .strip_prefix('{')
+        .and_then(|l| l.strip_suffix('}'))
+        .ok_or("expected a JSON object")?;
+
+    let mut fields = HashMap::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in body
+        .char_indices()
+        .chain(std::iter::once((body.len(), ',')))
+    {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                let field = &body[start..i];
+                let colon = field.find(':').ok_or("expected \"key\":value")?;
+                fields.insert(
+                    field[..colon].trim().trim_matches('"'),
+                    field[colon + 1..].trim(),
+                );
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(fields)
+}
+
+// Replays a log over the board it started from, under the rules it was recorded with, stopping
+// when `rounds` rounds have finished (or at the end of the log, if the battle ended first).  Every
+// event is checked against the board as it stands, so a log from a different engine fails at the
+// first point the two disagree.
+pub fn replay(
+    board: Vec<Vec<Unit>>,
+    rules: &CombatRules,
+    events: &[Event],
+    rounds: usize,
+) -> Result<Vec<Vec<Unit>>, String> {
+    let mut board = rules.starting_board(board);
+    for (index, event) in events.iter().enumerate() {
+        let fail = |message: String| format!("event {} ({:?}): {}", index + 1, event, message);
+        let unit_at = |board: &[Vec<Unit>], (row, col): (usize, usize)| match board
+            .get(row)
+            .and_then(|r| r.get(col))
+        {
+            Some(&unit @ Fighter { .. }) => Ok(unit),
+            other => Err(fail(format!(
+                "expected a unit at {}, {}, found {:?}",
+                row, col, other
+            ))),
+        };
+
+        match *event {
+            Event::RoundStarted { round } if round >= rounds => break,
+            Event::RoundStarted { .. } => {}
+            Event::UnitMoved { from, to } => {
+                let unit = unit_at(&board, from)?;
+                if board.get(to.0).and_then(|r| r.get(to.1)) != Some(&Empty) {
+                    return Err(fail(format!("{}, {} is not empty", to.0, to.1)));
+                }
+                board[from.0][from.1] = Empty;
+                board[to.0][to.1] = unit;
+            }
+            Event::UnitAttacked {
+                attacker,
+                target,
+                damage,
+                hp_left,
+            } => {
+                unit_at(&board, attacker)?;
+                board[target.0][target.1] = match unit_at(&board, target)? {
+                    Fighter { faction, id, hp } if hp.checked_sub(damage) == Some(hp_left) => {
+                        Fighter {
+                            faction,
+                            id,
+                            hp: hp_left,
+                        }
+                    }
+                    unit => {
+                        return Err(fail(format!(
+                            "{:?} would not be left with {} hit points",
+                            unit, hp_left
+                        )))
+                    }
+                };
+            }
+            Event::UnitDied { position } => match unit_at(&board, position)? {
+                Fighter { hp: 0, .. } => board[position.0][position.1] = Empty,
+                unit => return Err(fail(format!("{:?} is still alive", unit))),
+            },
+            Event::CombatEnded { .. } => {}
+        }
+    }
+    Ok(board)
+}
+
+// The board the way the puzzle shows it, with each row's hit points alongside.
+pub fn render(board: &[Vec<Unit>]) -> String {
+    let mut out = String::new();
+    for row in board {
+        let mut hp = Vec::new();
+        for unit in row {
+            out.push(match *unit {
+                Wall => '#',
+                Empty => '.',
+                Fighter { faction, hp: x, .. } => {
+                    hp.push(format!("{}({})", faction.letter(), x));
+                    faction.letter()
+                }
+            });
+        }
+        if !hp.is_empty() {
+            out.push_str("   ");
+            out.push_str(&hp.join(", "));
+        }
+        out.push('\n');
+    }
+    out
+}
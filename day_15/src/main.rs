@@ -4,35 +4,245 @@ extern crate nom;
 #[macro_use]
 extern crate log;
 
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::io::Read;
 
 use nom::types::CompleteByteSlice;
 
+mod events;
+
+use events::Event;
+
+// Fighters carry an id, numbered in reading order when the map is parsed, so that they can be
+// followed around the board.
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum Unit {
-    Elf(usize),
-    Goblin(usize),
+    Fighter {
+        faction: Faction,
+        id: usize,
+        hp: usize,
+    },
     Wall,
     Empty,
 }
 
+// A side in the battle, named by the capital letter that marks its units on the map.  The puzzle
+// only has elves and goblins, but any other letter makes a faction too.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct Faction(char);
+
+impl Faction {
+    const ELVES: Faction = Faction('E');
+    const GOBLINS: Faction = Faction('G');
+
+    fn letter(self) -> char {
+        self.0
+    }
+}
+
+impl fmt::Display for Faction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Faction::ELVES => write!(f, "Elves"),
+            Faction::GOBLINS => write!(f, "Goblins"),
+            Faction(letter) => write!(f, "{}", letter),
+        }
+    }
+}
+
+impl Unit {
+    fn faction(self) -> Option<Faction> {
+        match self {
+            Fighter { faction, .. } => Some(faction),
+            Wall | Empty => None,
+        }
+    }
+
+    fn id(self) -> Option<usize> {
+        match self {
+            Fighter { id, .. } => Some(id),
+            Wall | Empty => None,
+        }
+    }
+
+    // Zero for anything that isn't a fighter.
+    fn hp(self) -> usize {
+        match self {
+            Fighter { hp, .. } => hp,
+            Wall | Empty => 0,
+        }
+    }
+}
+
+// Listed in the reading order of the squares they lead to.
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
 enum Direction {
+    UpLeft,
     Up,
+    UpRight,
     Left,
     Right,
+    DownLeft,
     Down,
+    DownRight,
 }
 
 use self::Direction::*;
 use self::Unit::*;
 
+impl Direction {
+    fn offset(self) -> (isize, isize) {
+        match self {
+            UpLeft => (-1, -1),
+            Up => (-1, 0),
+            UpRight => (-1, 1),
+            Left => (0, -1),
+            Right => (0, 1),
+            DownLeft => (1, -1),
+            Down => (1, 0),
+            DownRight => (1, 1),
+        }
+    }
+
+    // The square one step this way.  Stepping off the top or left edge wraps around to an index
+    // far past the other edge, which is never on the board.
+    fn step(self, (row, col): (usize, usize)) -> (usize, usize) {
+        let (rows, cols) = self.offset();
+        (
+            row.wrapping_add(rows as usize),
+            col.wrapping_add(cols as usize),
+        )
+    }
+
+    // The square that a step this way came from.
+    fn back(self, (row, col): (usize, usize)) -> (usize, usize) {
+        let (rows, cols) = self.offset();
+        (
+            row.wrapping_sub(rows as usize),
+            col.wrapping_sub(cols as usize),
+        )
+    }
+}
+
+// The puzzle's rules: every unit starts with 200 hit points and hits for 3.  At 200 attack power a
+// unit kills with every blow, so raising it further changes nothing.
+const STARTING_HP: usize = 200;
+const ATTACK_POWER: usize = 3;
+
+// Which of several enemies in reach a unit attacks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Tiebreak {
+    // the puzzle's rule: the one with the fewest hit points, then the first in reading order
+    FewestHp,
+    // the one with the most hit points, then the first in reading order
+    MostHp,
+    // the first in reading order, whatever its hit points
+    ReadingOrder,
+}
+
+// How a battle is fought.  The default is the puzzle's rules; a faction missing from either map
+// gets the puzzle's numbers too.
+#[derive(Clone, Debug, PartialEq)]
+struct CombatRules {
+    starting_hp: BTreeMap<Faction, usize>,
+    attack_power: BTreeMap<Faction, usize>,
+    tiebreak: Tiebreak,
+    // Whether units may also move and attack diagonally.
+    diagonal: bool,
+    // Stop after this many full rounds, even if both sides are still standing.
+    max_rounds: Option<usize>,
+    // The battle is abandoned, returning None, as soon as a unit of one of these would die.
+    no_losses: BTreeSet<Faction>,
+    // Factions that fight on the same side.  Every faction is hostile to every other one that
+    // doesn't share an alliance with it, so with none listed it's every faction for itself.
+    alliances: Vec<BTreeSet<Faction>>,
+}
+
+impl Default for CombatRules {
+    fn default() -> Self {
+        CombatRules {
+            starting_hp: BTreeMap::new(),
+            attack_power: BTreeMap::new(),
+            tiebreak: Tiebreak::FewestHp,
+            diagonal: false,
+            max_rounds: None,
+            no_losses: BTreeSet::new(),
+            alliances: Vec::new(),
+        }
+    }
+}
+
+impl CombatRules {
+    fn starting_hp(&self, faction: Faction) -> usize {
+        self.starting_hp
+            .get(&faction)
+            .cloned()
+            .unwrap_or(STARTING_HP)
+    }
+
+    fn attack_power(&self, faction: Faction) -> usize {
+        self.attack_power
+            .get(&faction)
+            .cloned()
+            .unwrap_or(ATTACK_POWER)
+    }
+
+    fn hostile(&self, faction: Faction, other: Faction) -> bool {
+        faction != other
+            && !self
+                .alliances
+                .iter()
+                .any(|alliance| alliance.contains(&faction) && alliance.contains(&other))
+    }
+
+    // Whether any two factions that still have units standing are hostile to each other.
+    // Alliances may overlap, so this isn't the same as any one unit having an enemy left.
+    fn at_war(&self, alive: &BTreeMap<Faction, usize>) -> bool {
+        let standing = || {
+            alive
+                .iter()
+                .filter(|&(_, &count)| count > 0)
+                .map(|(&f, _)| f)
+        };
+        standing().any(|faction| standing().any(|other| self.hostile(faction, other)))
+    }
+
+    // Whether `unit` is a fighter that one of `faction` should attack.
+    fn is_enemy(&self, faction: Faction, unit: Unit) -> bool {
+        match unit {
+            Fighter { faction: other, .. } => self.hostile(faction, other),
+            Wall | Empty => false,
+        }
+    }
+
+    // The squares a unit can step to or attack, in reading order.
+    fn directions(&self) -> &'static [Direction] {
+        if self.diagonal {
+            &[UpLeft, Up, UpRight, Left, Right, DownLeft, Down, DownRight]
+        } else {
+            &[Up, Left, Right, Down]
+        }
+    }
+
+    // The board with every unit at its faction's starting hit points.
+    fn starting_board(&self, mut board: Vec<Vec<Unit>>) -> Vec<Vec<Unit>> {
+        for unit in board.iter_mut().flatten() {
+            if let Fighter { faction, hp, .. } = unit {
+                *hp = self.starting_hp(*faction);
+            }
+        }
+        board
+    }
+}
+
 named!(unit(CompleteByteSlice) -> Unit,
     alt!(
         do_parse!(tag!(&b"#"[..]) >> (Wall)) |
-        do_parse!(tag!(&b"E"[..]) >> (Elf(200))) |
-        do_parse!(tag!(&b"G"[..]) >> (Goblin(200))) |
+        do_parse!(
+            letter: one_of!("ABCDEFGHIJKLMNOPQRSTUVWXYZ") >>
+            (Fighter { faction: Faction(letter), id: 0, hp: STARTING_HP })
+        ) |
         do_parse!(tag!(&b"."[..]) >> (Empty))
     )
 );
@@ -46,10 +256,20 @@ named!(board(CompleteByteSlice) -> Vec<Vec<Unit>>,
                 (row)
             )
         ) >>
-        (rows)
+        (number_units(rows))
     )
 );
 
+fn number_units(mut board: Vec<Vec<Unit>>) -> Vec<Vec<Unit>> {
+    let mut next_id = 0..;
+    for unit in board.iter_mut().flatten() {
+        if let Fighter { id, .. } = unit {
+            *id = next_id.next().expect("ids never run out");
+        }
+    }
+    board
+}
+
 #[test]
 fn test_parser() {
     let input = b"#######
@@ -63,24 +283,60 @@ fn test_parser() {
         board,
         vec![
             vec![Wall, Wall, Wall, Wall, Wall, Wall, Wall],
-            vec![Wall, Elf(200), Empty, Empty, Goblin(200), Empty, Wall],
+            vec![
+                Wall,
+                Fighter {
+                    faction: Faction::ELVES,
+                    id: 0,
+                    hp: 200
+                },
+                Empty,
+                Empty,
+                Fighter {
+                    faction: Faction::GOBLINS,
+                    id: 1,
+                    hp: 200
+                },
+                Empty,
+                Wall
+            ],
             vec![Wall, Empty, Empty, Empty, Wall, Empty, Wall],
-            vec![Wall, Empty, Goblin(200), Empty, Wall, Goblin(200), Wall],
+            vec![
+                Wall,
+                Empty,
+                Fighter {
+                    faction: Faction::GOBLINS,
+                    id: 2,
+                    hp: 200
+                },
+                Empty,
+                Wall,
+                Fighter {
+                    faction: Faction::GOBLINS,
+                    id: 3,
+                    hp: 200
+                },
+                Wall
+            ],
             vec![Wall, Wall, Wall, Wall, Wall, Wall, Wall],
         ]
     );
 }
 
-fn get_enemy(board: &[Vec<Unit>], (row, col): (usize, usize)) -> std::mem::Discriminant<Unit> {
+fn get_faction(board: &[Vec<Unit>], (row, col): (usize, usize)) -> Faction {
     match board.get(row).and_then(|r| r.get(col)) {
-        Some(Goblin(_)) => std::mem::discriminant(&Elf(0)),
-        Some(Elf(_)) => std::mem::discriminant(&Goblin(0)),
-        x => panic!("Cell {}, {} was {:?}, neither Goblin nor Elf", row, col, x),
+        Some(Fighter { faction, .. }) => *faction,
+        x => panic!("Cell {}, {} was {:?}, not a fighter", row, col, x),
     }
 }
 
-fn next_step(board: &[Vec<Unit>], position: (usize, usize)) -> Option<Direction> {
-    let enemy = get_enemy(board, position);
+fn next_step(
+    board: &[Vec<Unit>],
+    rules: &CombatRules,
+    position: (usize, usize),
+) -> Option<Direction> {
+    let faction = get_faction(board, position);
+    let is_enemy = |unit| rules.is_enemy(faction, unit);
 
     // We want to move to the target that is first in the reading order, so we'll scan for the
     // reachable squares in reading order too.  Note that an enemy's "up" square is always going to
@@ -92,64 +348,32 @@ fn next_step(board: &[Vec<Unit>], position: (usize, usize)) -> Option<Direction>
 
     while !to_visit.is_empty() {
         let entry = *to_visit.iter().next().expect("to_visit is not empty");
-        let ((row, col), distance) = entry;
+        let (here, distance) = entry;
         to_visit.remove(&entry);
 
-        next_step_visit(
-            board,
-            row.wrapping_sub(1),
-            col,
-            distance,
-            enemy,
-            &mut to_visit,
-            &mut predecessors,
-            Up,
-            &mut reachable_enemies,
-        );
-        next_step_visit(
-            board,
-            row,
-            col.wrapping_sub(1),
-            distance,
-            enemy,
-            &mut to_visit,
-            &mut predecessors,
-            Left,
-            &mut reachable_enemies,
-        );
-        next_step_visit(
-            board,
-            row,
-            col + 1,
-            distance,
-            enemy,
-            &mut to_visit,
-            &mut predecessors,
-            Right,
-            &mut reachable_enemies,
-        );
-        next_step_visit(
-            board,
-            row + 1,
-            col,
-            distance,
-            enemy,
-            &mut to_visit,
-            &mut predecessors,
-            Down,
-            &mut reachable_enemies,
-        );
+        for &direction in rules.directions() {
+            let (row, col) = direction.step(here);
+            next_step_visit(
+                board,
+                row,
+                col,
+                distance,
+                &is_enemy,
+                &mut to_visit,
+                &mut predecessors,
+                direction,
+                &mut reachable_enemies,
+            );
+        }
     }
 
     let adjacent_to_enemy = reachable_enemies
         .iter()
-        .flat_map(|&(row, col)| {
-            vec![
-                (row.wrapping_sub(1), col),
-                (row, col.wrapping_sub(1)),
-                (row, col + 1),
-                (row + 1, col),
-            ]
+        .flat_map(|&enemy| {
+            rules
+                .directions()
+                .iter()
+                .map(move |direction| direction.step(enemy))
         })
         .filter(|position| predecessors.contains_key(position))
         // TODO: I might need to actually emit _all_ of the ones of the same distance, rather than
@@ -157,35 +381,30 @@ fn next_step(board: &[Vec<Unit>], position: (usize, usize)) -> Option<Direction>
         .min_by_key(|position| predecessors.get(position).expect("just filtered").1)?;
 
     // Walk the path to determine the original step
-    let (mut row, mut col) = adjacent_to_enemy;
+    let mut here = adjacent_to_enemy;
     let mut direction = None;
-    while (row, col) != position {
-        direction = Some(predecessors.get(&(row, col)).unwrap().0);
-        let predecessor_position = match direction.unwrap() {
-            Up => (row + 1, col),
-            Left => (row, col + 1),
-            Right => (row, col.wrapping_sub(1)),
-            Down => (row.wrapping_sub(1), col),
-        };
-
-        row = predecessor_position.0;
-        col = predecessor_position.1;
+    while here != position {
+        let step = predecessors.get(&here).unwrap().0;
+        direction = Some(step);
+        here = step.back(here);
     }
 
     direction
 }
 
-fn next_step_visit(
+fn next_step_visit<F>(
     board: &[Vec<Unit>],
     row: usize,
     col: usize,
     distance: usize,
-    enemy: std::mem::Discriminant<Unit>,
+    is_enemy: &F,
     to_visit: &mut BTreeSet<((usize, usize), usize)>,
     predecessors: &mut HashMap<(usize, usize), (Direction, usize)>,
     step_direction: Direction,
     reachable_enemies: &mut BTreeSet<(usize, usize)>,
-) {
+) where
+    F: Fn(Unit) -> bool,
+{
     let cell = *board.get(row).and_then(|r| r.get(col)).unwrap_or(&Wall);
 
     let mut update_predecessor = || -> bool {
@@ -201,11 +420,11 @@ fn next_step_visit(
     };
 
     match cell {
-        x if std::mem::discriminant(&x) == enemy => {
+        x if is_enemy(x) => {
             update_predecessor();
             reachable_enemies.insert((row, col));
         }
-        Goblin(_) | Elf(_) => {}
+        Fighter { .. } => {}
         Wall => {}
         Empty => {
             if update_predecessor() {
@@ -223,7 +442,10 @@ fn test_next_step() {
 #...G.#
 #######";
     let (_remaining, board) = board(CompleteByteSlice(&input[..])).unwrap();
-    assert_eq!(next_step(&board, (1, 2)), Some(Right));
+    assert_eq!(
+        next_step(&board, &CombatRules::default(), (1, 2)),
+        Some(Right)
+    );
 }
 
 #[derive(Debug, PartialEq)]
@@ -233,34 +455,29 @@ enum Action {
     Nothing,
 }
 
-fn next_action(board: &[Vec<Unit>], (row, col): (usize, usize)) -> Action {
-    let enemy = get_enemy(board, (row, col));
+fn next_action(board: &[Vec<Unit>], rules: &CombatRules, position: (usize, usize)) -> Action {
+    let faction = get_faction(board, position);
 
-    let attack = [
-        (row.wrapping_sub(1), col, Up),
-        (row, col.wrapping_sub(1), Left),
-        (row, col + 1, Right),
-        (row + 1, col, Down),
-    ]
-    .iter()
-    .cloned()
-    .filter_map(|(other_row, other_col, dir)| {
+    // in reading order, so that the first of equals wins
+    let in_reach = rules.directions().iter().filter_map(|&dir| {
+        let (other_row, other_col) = dir.step(position);
         board
             .get(other_row)
             .and_then(|r| r.get(other_col))
-            .filter(|&unit| std::mem::discriminant(unit) == enemy)
+            .filter(|&&unit| rules.is_enemy(faction, unit))
             .map(|&unit| (unit, dir))
-    })
-    .min_by_key(|&(unit, _dir)| match unit {
-        Goblin(x) | Elf(x) => x,
-        _ => panic!("should have filtered out enemies before we get here"),
     });
+    let attack = match rules.tiebreak {
+        Tiebreak::FewestHp => in_reach.min_by_key(|&(unit, _dir)| unit.hp()),
+        Tiebreak::MostHp => in_reach.min_by_key(|&(unit, _dir)| std::cmp::Reverse(unit.hp())),
+        Tiebreak::ReadingOrder => in_reach.take(1).next(),
+    };
 
     if let Some((_, dir)) = attack {
         return Action::Attack(dir);
     }
 
-    if let Some(dir) = next_step(board, (row, col)) {
+    if let Some(dir) = next_step(board, rules, position) {
         return Action::Move(dir);
     }
 
@@ -269,6 +486,7 @@ fn next_action(board: &[Vec<Unit>], (row, col): (usize, usize)) -> Action {
 
 #[test]
 fn test_next_action() {
+    let rules = CombatRules::default();
     let input = b"#######
 #.E...#
 #.....#
@@ -276,7 +494,7 @@ fn test_next_action() {
 #######";
     let (_remaining, b) = board(CompleteByteSlice(&input[..])).unwrap();
 
-    assert_eq!(next_action(&b, (1, 2)), Action::Move(Right));
+    assert_eq!(next_action(&b, &rules, (1, 2)), Action::Move(Right));
 
     let input = b"#########
 #.......#
@@ -288,7 +506,7 @@ fn test_next_action() {
 #.......#
 #########";
     let (_remaining, b) = board(CompleteByteSlice(&input[..])).unwrap();
-    assert_eq!(next_action(&b, (3, 3)), Action::Attack(Right));
+    assert_eq!(next_action(&b, &rules, (3, 3)), Action::Attack(Right));
 
     let input = b"#########
 #.......#
@@ -300,7 +518,7 @@ fn test_next_action() {
 #.......#
 #########";
     let (_remaining, b) = board(CompleteByteSlice(&input[..])).unwrap();
-    assert_eq!(next_action(&b, (3, 3)), Action::Nothing);
+    assert_eq!(next_action(&b, &rules, (3, 3)), Action::Nothing);
 
     /* From a failing case demonstrated in test_run():
         #######
@@ -322,77 +540,229 @@ fn test_next_action() {
 #.....#
 #######";
     let (_remaining, b) = board(CompleteByteSlice(&input[..])).unwrap();
-    assert_eq!(next_action(&b, (4, 3)), Action::Move(Up));
+    assert_eq!(next_action(&b, &rules, (4, 3)), Action::Move(Up));
+}
+
+#[test]
+fn test_next_action_rules() {
+    let mut rules = CombatRules::default();
+    let input = b"#####
+#.G.#
+#GEG#
+#####";
+    let (_remaining, mut b) = board(CompleteByteSlice(&input[..])).unwrap();
+    for &((row, col), new_hp) in &[((1, 2), 150), ((2, 1), 50), ((2, 3), 200)] {
+        if let Fighter { hp, .. } = &mut b[row][col] {
+            *hp = new_hp;
+        }
+    }
+    assert_eq!(next_action(&b, &rules, (2, 2)), Action::Attack(Left));
+    rules.tiebreak = Tiebreak::MostHp;
+    assert_eq!(next_action(&b, &rules, (2, 2)), Action::Attack(Right));
+    rules.tiebreak = Tiebreak::ReadingOrder;
+    assert_eq!(next_action(&b, &rules, (2, 2)), Action::Attack(Up));
+
+    let mut rules = CombatRules::default();
+    let input = b"#####
+#E..#
+#.G.#
+#####";
+    let (_remaining, b) = board(CompleteByteSlice(&input[..])).unwrap();
+    assert_eq!(next_action(&b, &rules, (1, 1)), Action::Move(Right));
+    rules.diagonal = true;
+    assert_eq!(next_action(&b, &rules, (1, 1)), Action::Attack(DownRight));
+    assert_eq!(next_action(&b, &rules, (2, 2)), Action::Attack(UpLeft));
+
+    let input = b"#####
+#E..#
+#...#
+#..G#
+#####";
+    let (_remaining, b) = board(CompleteByteSlice(&input[..])).unwrap();
+    assert_eq!(next_action(&b, &rules, (1, 1)), Action::Move(DownRight));
+}
+
+// What one unit did over a battle.  Damage counts the hit points actually lost, so a killing blow
+// counts for no more than the target had left.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct UnitStats {
+    faction: Faction,
+    hp_left: usize,
+    damage_dealt: usize,
+    damage_taken: usize,
+    kills: usize,
+    tiles_walked: usize,
+    // 1-based, as the puzzle counts rounds
+    died_in_round: Option<usize>,
+}
+
+// The same, added up over every unit on one side.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct FactionSummary {
+    units: usize,
+    survivors: usize,
+    hp_left: usize,
+    damage_dealt: usize,
+    damage_taken: usize,
+    kills: usize,
+    tiles_walked: usize,
+}
+
+// A battle that was fought to the end.
+#[derive(Clone, Debug, PartialEq)]
+struct Battle {
+    // full rounds completed
+    rounds: usize,
+    outcome: usize,
+    // whether the battle was cut short by the round limit
+    timed_out: bool,
+    units: BTreeMap<usize, UnitStats>,
+}
+
+impl Battle {
+    fn new(board: &[Vec<Unit>]) -> Self {
+        let units = board
+            .iter()
+            .flatten()
+            .filter_map(|&unit| {
+                let stats = UnitStats {
+                    faction: unit.faction()?,
+                    hp_left: unit.hp(),
+                    damage_dealt: 0,
+                    damage_taken: 0,
+                    kills: 0,
+                    tiles_walked: 0,
+                    died_in_round: None,
+                };
+                Some((unit.id()?, stats))
+            })
+            .collect();
+
+        Battle {
+            rounds: 0,
+            outcome: 0,
+            timed_out: false,
+            units,
+        }
+    }
+
+    fn unit(&mut self, unit: Unit) -> &mut UnitStats {
+        let id = unit.id().expect("only fighters have stats");
+        self.units
+            .get_mut(&id)
+            .expect("every unit was on the board at the start")
+    }
+
+    fn factions(&self) -> BTreeMap<Faction, FactionSummary> {
+        let mut factions = BTreeMap::<Faction, FactionSummary>::new();
+        for stats in self.units.values() {
+            let summary = factions.entry(stats.faction).or_default();
+            summary.units += 1;
+            if stats.died_in_round.is_none() {
+                summary.survivors += 1;
+            }
+            summary.hp_left += stats.hp_left;
+            summary.damage_dealt += stats.damage_dealt;
+            summary.damage_taken += stats.damage_taken;
+            summary.kills += stats.kills;
+            summary.tiles_walked += stats.tiles_walked;
+        }
+        factions
+    }
 }
 
-fn attack<T>(
-    board: &mut Vec<Vec<Unit>>,
-    attack_power: T,
+// Returns None, leaving the board as it was, only if the rules say to abandon the battle.
+fn attack<E>(
+    board: &mut [Vec<Unit>],
+    rules: &CombatRules,
     (row, col): (usize, usize),
     dir: Direction,
-    elves: &mut usize,
-    goblins: &mut usize,
+    alive: &mut BTreeMap<Faction, usize>,
+    battle: &mut Battle,
+    on_event: &mut E,
 ) -> Option<()>
 where
-    T: FnOnce(&Unit, &Unit) -> Option<usize>,
+    E: FnMut(Event),
 {
-    let (other_row, other_col) = match dir {
-        Left => (row, col - 1),
-        Right => (row, col + 1),
-        Down => (row + 1, col),
-        Up => (row - 1, col),
-    };
+    let (other_row, other_col) = dir.step((row, col));
 
-    let this_attack = attack_power(&board[row][col], &board[other_row][other_col])?;
+    let attacker = board[row][col];
+    let target = board[other_row][other_col];
+    let this_attack = rules.attack_power(attacker.faction().expect("only fighters attack"));
 
-    let new_unit = match board[other_row][other_col] {
-        Goblin(x) => {
-            if x <= this_attack {
-                *goblins -= 1;
-                Empty
-            } else {
-                Goblin(x - this_attack)
-            }
-        }
-        Elf(x) => {
-            if x <= this_attack {
-                *elves -= 1;
-                Empty
-            } else {
-                Elf(x - this_attack)
-            }
-        }
+    let hp_left = target.hp().saturating_sub(this_attack);
+    let target_faction = target.faction().expect("only fighters are attacked");
+    if hp_left == 0 && rules.no_losses.contains(&target_faction) {
+        return None;
+    }
+    let new_unit = match target {
+        _ if hp_left == 0 => Empty,
+        Fighter { faction, id, .. } => Fighter {
+            faction,
+            id,
+            hp: hp_left,
+        },
         something_else => panic!("Tried to attack a {:?}", something_else),
     };
 
+    let damage = target.hp() - hp_left;
+    battle.unit(attacker).damage_dealt += damage;
+    let round = battle.rounds + 1;
+    let stats = battle.unit(target);
+    stats.damage_taken += damage;
+    stats.hp_left = hp_left;
+
+    on_event(Event::UnitAttacked {
+        attacker: (row, col),
+        target: (other_row, other_col),
+        damage,
+        hp_left,
+    });
+    if new_unit == Empty {
+        stats.died_in_round = Some(round);
+        battle.unit(attacker).kills += 1;
+        if let Some(count) = alive.get_mut(&target_faction) {
+            *count -= 1;
+        }
+        on_event(Event::UnitDied {
+            position: (other_row, other_col),
+        });
+    }
+
     board[other_row][other_col] = new_unit;
     Some(())
 }
 
-fn run_with_attack_power<T>(mut board: Vec<Vec<Unit>>, mut attack_power: T) -> Option<usize>
+// Runs a battle to the end under `rules`, telling `on_event` about everything that happens along
+// the way.  Returns None if the rules say to abandon it.
+fn run_with_attack_power<E>(
+    board: Vec<Vec<Unit>>,
+    rules: &CombatRules,
+    mut on_event: E,
+) -> Option<Battle>
 where
-    T: FnMut(&Unit, &Unit) -> Option<usize>,
+    E: FnMut(Event),
 {
-    let mut rounds = 0;
+    let mut board = rules.starting_board(board);
+    let mut battle = Battle::new(&board);
 
     'round: loop {
-        debug!("=== Starting round {}", rounds);
+        if rules.max_rounds == Some(battle.rounds) {
+            battle.timed_out = true;
+            break;
+        }
+        debug!("=== Starting round {}", battle.rounds);
+        on_event(Event::RoundStarted {
+            round: battle.rounds,
+        });
 
         let mut players = Vec::new();
-        let mut goblins = 0;
-        let mut elves = 0;
+        let mut alive = BTreeMap::new();
         for (row_num, row) in board.iter().enumerate() {
             for (col_num, unit) in row.iter().enumerate() {
-                match unit {
-                    Goblin(_) => {
-                        goblins += 1;
-                        players.push((row_num, col_num));
-                    }
-                    Elf(_) => {
-                        elves += 1;
-                        players.push((row_num, col_num));
-                    }
-                    _ => {}
+                if let Some(faction) = unit.faction() {
+                    *alive.entry(faction).or_insert(0) += 1;
+                    players.push((row_num, col_num));
                 }
             }
         }
@@ -404,39 +774,48 @@ where
                     debug!("skipped {}, {}", row, col);
                     continue;
                 }
-                Goblin(_) | Elf(_) if goblins == 0 || elves == 0 => {
-                    // No opponents left, and the round did not complete.
+                Fighter { .. } if !rules.at_war(&alive) => {
+                    // Only one alliance is left standing, and the round did not complete.
                     break 'round;
                 }
+                Fighter { faction, .. }
+                    if !alive
+                        .iter()
+                        .any(|(&other, &count)| count > 0 && rules.hostile(faction, other)) =>
+                {
+                    debug!("{}, {} has nobody left to fight", row, col);
+                    continue;
+                }
                 _ => {}
             }
 
-            let action = next_action(&board, (row, col));
+            let action = next_action(&board, rules, (row, col));
             dump_board(&board, (row, col));
             debug!("{}, {} decided to {:?}", row, col, action);
 
             match action {
                 Action::Move(dir) => {
-                    let (new_row, new_col) = match dir {
-                        Left => (row, col - 1),
-                        Right => (row, col + 1),
-                        Down => (row + 1, col),
-                        Up => (row - 1, col),
-                    };
+                    let (new_row, new_col) = dir.step((row, col));
                     let unit = board[row][col];
                     board[row][col] = Empty;
                     board[new_row][new_col] = unit;
+                    battle.unit(unit).tiles_walked += 1;
+                    on_event(Event::UnitMoved {
+                        from: (row, col),
+                        to: (new_row, new_col),
+                    });
 
-                    if let Action::Attack(dir) = next_action(&board, (new_row, new_col)) {
+                    if let Action::Attack(dir) = next_action(&board, rules, (new_row, new_col)) {
                         debug!("{}, {} would now attack {:?}", new_row, new_col, dir);
                         attack(
                             &mut board,
-                            &mut attack_power,
+                            rules,
                             (new_row, new_col),
                             dir,
-                            &mut elves,
-                            &mut goblins,
-                        );
+                            &mut alive,
+                            &mut battle,
+                            &mut on_event,
+                        )?;
                     } else {
                         debug!(
                             "{}, {} still can't attack anything this round",
@@ -446,37 +825,42 @@ where
                 }
                 Action::Attack(dir) => attack(
                     &mut board,
-                    &mut attack_power,
+                    rules,
                     (row, col),
                     dir,
-                    &mut elves,
-                    &mut goblins,
+                    &mut alive,
+                    &mut battle,
+                    &mut on_event,
                 )?,
                 Action::Nothing => {}
             }
             debug!("");
         }
 
-        rounds += 1;
+        battle.rounds += 1;
     }
 
-    let sum_hp: usize = board
-        .iter()
-        .flat_map(|r| {
-            r.iter().map(|&unit| match unit {
-                Goblin(x) | Elf(x) => x,
-                _ => 0,
-            })
-        })
-        .sum();
+    let sum_hp: usize = board.iter().flatten().map(|unit| unit.hp()).sum();
+    let rounds = battle.rounds;
+    battle.outcome = rounds * sum_hp;
 
     debug!("sum is {}, rounds is {}", sum_hp, rounds);
+    for (faction, summary) in battle.factions() {
+        info!("{:?}: {:?}", faction, summary);
+    }
+    on_event(Event::CombatEnded {
+        rounds,
+        hp_left: sum_hp,
+        outcome: battle.outcome,
+    });
 
-    Some(rounds * sum_hp)
+    Some(battle)
 }
 
 fn run(board: Vec<Vec<Unit>>) -> usize {
-    run_with_attack_power(board, |_, _| Some(3)).expect("closure never returns None")
+    run_with_attack_power(board, &CombatRules::default(), |_| {})
+        .expect("the puzzle's rules never abandon a battle")
+        .outcome
 }
 
 #[cfg(test)]
@@ -582,19 +966,228 @@ fn test_run_6() {
     );
 }
 
-fn run_without_killing_elf(board: Vec<Vec<Unit>>) -> usize {
-    for elf_attack_power in 4.. {
-        let attack_power = |_attacker: &Unit, receiver: &Unit| -> Option<usize> {
-            match *receiver {
-                Elf(x) if x < 3 => None,
-                Elf(_) => Some(3),
-                Goblin(_) => Some(elf_attack_power),
-                wtf => panic!("Tried to attack a {:?}", wtf),
+#[test]
+fn test_combat_rules() {
+    let input = b"#######
+#.G...#
+#...EG#
+#.#.#G#
+#..G#E#
+#.....#
+#######";
+    let (_remaining, b) = board(CompleteByteSlice(&input[..])).unwrap();
+
+    // cut short after the second round of the puzzle's walkthrough
+    let rules = CombatRules {
+        max_rounds: Some(2),
+        ..CombatRules::default()
+    };
+    let battle = run_with_attack_power(b.clone(), &rules, |_| {}).unwrap();
+    assert_eq!(battle.rounds, 2);
+    assert!(battle.timed_out);
+    assert_eq!(battle.outcome, 2 * (200 + 200 + 188 + 194 + 194 + 194));
+
+    let battle = run_with_attack_power(b.clone(), &CombatRules::default(), |_| {}).unwrap();
+    assert!(!battle.timed_out);
+    assert_eq!(battle.outcome, 27730);
+
+    // the same as the second part of the puzzle, at 15
+    let mut rules = CombatRules::default();
+    rules.attack_power.insert(Faction::ELVES, 15);
+    let battle = run_with_attack_power(b.clone(), &rules, |_| {}).unwrap();
+    assert_eq!(battle.outcome, 4988);
+
+    let mut rules = CombatRules::default();
+    rules.starting_hp.insert(Faction::GOBLINS, 1);
+    rules.no_losses.insert(Faction::ELVES);
+    let battle = run_with_attack_power(b, &rules, |_| {}).unwrap();
+    let factions = battle.factions();
+    assert_eq!(factions[&Faction::GOBLINS].survivors, 0);
+    assert_eq!(factions[&Faction::GOBLINS].damage_taken, 4);
+    assert_eq!(factions[&Faction::ELVES].survivors, 2);
+}
+
+#[test]
+fn test_factions() {
+    let input = b"#########
+#E.....G#
+#.......#
+#.......#
+#...O...#
+#########";
+    let (_remaining, b) = board(CompleteByteSlice(&input[..])).unwrap();
+    assert_eq!(
+        b[4][4],
+        Fighter {
+            faction: Faction('O'),
+            id: 2,
+            hp: 200
+        }
+    );
+
+    // every faction for itself, until only one is left
+    let battle = run_with_attack_power(b.clone(), &CombatRules::default(), |_| {}).unwrap();
+    let survivors: Vec<_> = battle
+        .factions()
+        .into_iter()
+        .filter(|(_, summary)| summary.survivors > 0)
+        .map(|(faction, _)| faction)
+        .collect();
+    assert_eq!(survivors, vec![Faction('O')]);
+    assert_eq!((battle.rounds, battle.outcome), (68, 7276));
+
+    // elves and orcs against the goblins
+    let mut rules = CombatRules::default();
+    rules
+        .alliances
+        .push(vec![Faction::ELVES, Faction('O')].into_iter().collect());
+    assert!(!rules.hostile(Faction::ELVES, Faction('O')));
+    assert!(rules.hostile(Faction::GOBLINS, Faction('O')));
+    let battle = run_with_attack_power(b.clone(), &rules, |_| {}).unwrap();
+    let factions = battle.factions();
+    let (elves, goblins, orcs) = (
+        factions[&Faction::ELVES],
+        factions[&Faction::GOBLINS],
+        factions[&Faction('O')],
+    );
+    assert_eq!((battle.rounds, battle.outcome), (35, 10535));
+    assert_eq!(
+        (elves.survivors, goblins.survivors, orcs.survivors),
+        (1, 0, 1)
+    );
+    assert_eq!(elves.damage_dealt + orcs.damage_dealt, goblins.damage_taken);
+    assert_eq!(goblins.damage_dealt, elves.damage_taken + orcs.damage_taken);
+
+    // allies on their own have nobody to fight
+    rules.alliances[0].insert(Faction::GOBLINS);
+    let battle = run_with_attack_power(b, &rules, |_| {}).unwrap();
+    assert_eq!((battle.rounds, battle.outcome), (0, 0));
+}
+
+#[test]
+fn test_overlapping_alliances() {
+    // The elves are allied with both of the others, who still have to fight it out.
+    let input = b"#######
+#E.G.O#
+#######";
+    let (_remaining, b) = board(CompleteByteSlice(&input[..])).unwrap();
+    let mut rules = CombatRules::default();
+    rules
+        .alliances
+        .push(vec![Faction::ELVES, Faction('O')].into_iter().collect());
+    rules
+        .alliances
+        .push(vec![Faction::ELVES, Faction::GOBLINS].into_iter().collect());
+
+    let mut alive = BTreeMap::new();
+    alive.insert(Faction::ELVES, 1);
+    alive.insert(Faction::GOBLINS, 1);
+    assert!(!rules.at_war(&alive));
+    alive.insert(Faction('O'), 1);
+    assert!(rules.at_war(&alive));
+    alive.insert(Faction::GOBLINS, 0);
+    assert!(!rules.at_war(&alive));
+
+    let battle = run_with_attack_power(b, &rules, |_| {}).unwrap();
+    let factions = battle.factions();
+    assert!(battle.rounds > 0);
+    assert_eq!(factions[&Faction::ELVES].damage_taken, 0);
+    assert_eq!(factions[&Faction::ELVES].damage_dealt, 0);
+    assert_eq!(
+        factions[&Faction::GOBLINS].survivors + factions[&Faction('O')].survivors,
+        1
+    );
+}
+
+// Runs the battle with goblins at the usual 3 attack power and elves at `elf_attack_power`.
+// Returns None as soon as an elf would die.
+fn run_with_elf_attack_power(board: Vec<Vec<Unit>>, elf_attack_power: usize) -> Option<usize> {
+    let mut rules = CombatRules::default();
+    rules.attack_power.insert(Faction::ELVES, elf_attack_power);
+    rules.no_losses.insert(Faction::ELVES);
+    run_with_attack_power(board, &rules, |_| {}).map(|battle| battle.outcome)
+}
+
+// The cheapest battle in which no elf dies.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Flawless {
+    elf_attack_power: usize,
+    outcome: usize,
+    // how many battles were fought to find it
+    simulations: usize,
+}
+
+// Elves that all survive at some attack power are assumed to survive at any higher one too, so
+// this doubles the power until they do, then bisects between the last loss and the first win.
+#[cfg_attr(feature = "rayon", allow(dead_code))]
+fn run_without_killing_elf(board: &[Vec<Unit>]) -> Flawless {
+    let mut simulations = 0;
+    let mut fight = |power| {
+        simulations += 1;
+        run_with_elf_attack_power(board.to_vec(), power)
+    };
+
+    // At 3, elves are no stronger than goblins; that's part 1.
+    let mut lost = 3;
+    let mut won = 4;
+    let mut outcome = loop {
+        match fight(won) {
+            Some(outcome) => break outcome,
+            None if won == STARTING_HP => panic!("No flawless Elf victory was ever observed"),
+            None => {
+                lost = won;
+                won = (won * 2).min(STARTING_HP);
             }
-        };
+        }
+    };
+
+    while won - lost > 1 {
+        let power = lost + (won - lost) / 2;
+        match fight(power) {
+            Some(better) => {
+                won = power;
+                outcome = better;
+            }
+            None => lost = power,
+        }
+    }
+
+    Flawless {
+        elf_attack_power: won,
+        outcome,
+        simulations,
+    }
+}
 
-        if let Some(ret) = run_with_attack_power(board.clone(), attack_power) {
-            return ret;
+// Tries attack powers in order, a batch of consecutive ones at a time across all of rayon's
+// threads.  Unlike the bisection, this finds the lowest power even if a stronger elf could
+// somehow lose a battle a weaker one wins, at the cost of more simulations.
+#[cfg(feature = "rayon")]
+fn sweep_without_killing_elf(board: &[Vec<Unit>]) -> Flawless {
+    use rayon::prelude::*;
+
+    let batch = rayon::current_num_threads();
+    let mut simulations = 0;
+    for first in (4..=STARTING_HP).step_by(batch) {
+        let powers: Vec<usize> = (first..first + batch)
+            .take_while(|&p| p <= STARTING_HP)
+            .collect();
+        let outcomes: Vec<Option<usize>> = powers
+            .par_iter()
+            .map(|&power| run_with_elf_attack_power(board.to_vec(), power))
+            .collect();
+        simulations += powers.len();
+
+        if let Some((power, outcome)) = powers
+            .iter()
+            .zip(outcomes)
+            .find_map(|(&power, outcome)| Some((power, outcome?)))
+        {
+            return Flawless {
+                elf_attack_power: power,
+                outcome,
+                simulations,
+            };
         }
     }
     panic!("No flawless Elf victory was ever observed")
@@ -614,7 +1207,231 @@ fn test_run_without_killing_elf() {
     )
     .unwrap()
     .1;
-    assert_eq!(run_without_killing_elf(b), 4988);
+    let flawless = run_without_killing_elf(&b);
+    assert_eq!(flawless.elf_attack_power, 15);
+    assert_eq!(flawless.outcome, 4988);
+    // 4, 8 and 16, then 12, 14 and 15
+    assert_eq!(flawless.simulations, 6);
+}
+
+#[test]
+fn test_run_without_killing_elf_examples() {
+    let examples: &[(&[u8], usize, usize)] = &[
+        (
+            b"#######
+#E..EG#
+#.#G.E#
+#E.##E#
+#G..#.#
+#..E#.#
+#######",
+            4,
+            31284,
+        ),
+        (
+            b"#######
+#E.G#.#
+#.#G..#
+#G.#.G#
+#G..#.#
+#...E.#
+#######",
+            15,
+            3478,
+        ),
+        (
+            b"#######
+#.E...#
+#.#..G#
+#.###.#
+#E#G#G#
+#...#G#
+#######",
+            12,
+            6474,
+        ),
+        (
+            b"#########
+#G......#
+#.E.#...#
+#..##..G#
+#...##..#
+#...#...#
+#.G...G.#
+#.....G.#
+#########",
+            34,
+            1140,
+        ),
+    ];
+
+    for &(input, power, outcome) in examples {
+        let b = board(CompleteByteSlice(input)).unwrap().1;
+        let flawless = run_without_killing_elf(&b);
+        assert_eq!(
+            (flawless.elf_attack_power, flawless.outcome),
+            (power, outcome)
+        );
+
+        #[cfg(feature = "rayon")]
+        {
+            let swept = sweep_without_killing_elf(&b);
+            assert_eq!((swept.elf_attack_power, swept.outcome), (power, outcome));
+        }
+    }
+}
+
+#[test]
+fn test_elf_killed_after_move() {
+    // The goblin steps next to the elf and kills it in the same turn.  If that attack were
+    // dropped, the elf would get to kill the goblin and the battle would look flawless.
+    let input = b"#####
+#G.E#
+#####";
+    let (_remaining, b) = board(CompleteByteSlice(&input[..])).unwrap();
+    let mut rules = CombatRules::default();
+    rules.starting_hp.insert(Faction::ELVES, 3);
+    rules.attack_power.insert(Faction::ELVES, 200);
+    rules.no_losses.insert(Faction::ELVES);
+    assert_eq!(run_with_attack_power(b.clone(), &rules, |_| {}), None);
+
+    rules.no_losses.clear();
+    let battle = run_with_attack_power(b, &rules, |_| {}).unwrap();
+    assert_eq!(battle.factions()[&Faction::ELVES].survivors, 0);
+    assert_eq!(battle.rounds, 1);
+}
+
+#[test]
+fn test_events() {
+    let b = board(
+        b"#######
+#.G...#
+#...EG#
+#.#.#G#
+#..G#E#
+#.....#
+#######"[..]
+            .into(),
+    )
+    .unwrap()
+    .1;
+    let mut log = Vec::new();
+    run_with_attack_power(b.clone(), &CombatRules::default(), |event| log.push(event));
+
+    assert_eq!(
+        log.last(),
+        Some(&Event::CombatEnded {
+            rounds: 47,
+            hp_left: 590,
+            outcome: 27730,
+        })
+    );
+    for event in &log {
+        assert_eq!(Event::from_json(&event.to_json()).as_ref(), Ok(event));
+    }
+
+    let after = |rounds| {
+        events::render(&events::replay(b.clone(), &CombatRules::default(), &log, rounds).unwrap())
+    };
+    assert_eq!(
+        after(2),
+        "#######
+#...G.#   G(200)
+#..GEG#   G(200), E(188), G(194)
+#.#.#G#   G(194)
+#...#E#   E(194)
+#.....#
+#######
+"
+    );
+    assert_eq!(
+        after(47),
+        "#######
+#G....#   G(200)
+#.G...#   G(131)
+#.#.#G#   G(59)
+#...#.#
+#....G#   G(200)
+#######
+"
+    );
+
+    // A log that disagrees with the board is caught where it first goes wrong.
+    let mut wrong = log.clone();
+    let first_attack = wrong
+        .iter()
+        .position(|event| matches!(event, Event::UnitAttacked { .. }))
+        .unwrap();
+    if let Event::UnitAttacked { damage, .. } = &mut wrong[first_attack] {
+        *damage = 4;
+    }
+    let error = events::replay(b.clone(), &CombatRules::default(), &wrong, 47).unwrap_err();
+    assert!(
+        error.starts_with(&format!("event {} ", first_attack + 1)),
+        "{}",
+        error
+    );
+
+    // Replaying needs the rules the log was recorded with.
+    let mut rules = CombatRules::default();
+    rules.starting_hp.insert(Faction::GOBLINS, 150);
+    let mut log = Vec::new();
+    run_with_attack_power(b.clone(), &rules, |event| log.push(event)).unwrap();
+    assert!(events::replay(b.clone(), &CombatRules::default(), &log, 10).is_err());
+    let replayed = events::replay(b, &rules, &log, 1).unwrap();
+    assert_eq!((replayed[1][3].hp(), replayed[2][5].hp()), (150, 147));
+}
+
+#[test]
+fn test_unit_stats() {
+    let b = board(
+        b"#######
+#.G...#
+#...EG#
+#.#.#G#
+#..G#E#
+#.....#
+#######"[..]
+            .into(),
+    )
+    .unwrap()
+    .1;
+    let mut moves = 0;
+    let mut damage_logged = 0;
+    let battle = run_with_attack_power(b, &CombatRules::default(), |event| match event {
+        Event::UnitMoved { .. } => moves += 1,
+        Event::UnitAttacked { damage, .. } => damage_logged += damage,
+        _ => {}
+    })
+    .unwrap();
+    assert_eq!((battle.rounds, battle.outcome), (47, 27730));
+
+    // Ids are given out in reading order, and the elves are 1 and 4.
+    assert_eq!(battle.units[&1].faction, Faction::ELVES);
+    assert_eq!(battle.units[&1].damage_taken, 200);
+    assert!(battle
+        .units
+        .values()
+        .all(|stats| if stats.faction == Faction::ELVES {
+            stats.died_in_round.is_some() && stats.hp_left == 0
+        } else {
+            stats.died_in_round.is_none()
+        }));
+
+    let factions = battle.factions();
+    let elves = factions[&Faction::ELVES];
+    let goblins = factions[&Faction::GOBLINS];
+    assert_eq!((elves.units, elves.survivors), (2, 0));
+    assert_eq!((goblins.units, goblins.survivors), (4, 4));
+    assert_eq!(goblins.hp_left, 590);
+    assert_eq!(elves.damage_dealt, goblins.damage_taken);
+    assert_eq!(goblins.damage_dealt, elves.damage_taken);
+    assert_eq!(goblins.damage_taken, 4 * 200 - 590);
+    assert_eq!((elves.kills, goblins.kills), (0, 2));
+
+    assert_eq!(elves.tiles_walked + goblins.tiles_walked, moves);
+    // the log counts killing blows the same way, by the hit points actually lost
+    assert_eq!(elves.damage_taken + goblins.damage_taken, damage_logged);
 }
 
 fn dump_board(board: &[Vec<Unit>], highlight_position: (usize, usize)) {
@@ -624,8 +1441,7 @@ fn dump_board(board: &[Vec<Unit>], highlight_position: (usize, usize)) {
             let c = match *col {
                 Wall => '#',
                 Empty => '.',
-                Goblin(_) => 'G',
-                Elf(_) => 'E',
+                Fighter { faction, .. } => faction.letter(),
             };
             if (cur_row, cur_col) == highlight_position {
                 line.push_str("\x1b[1m");
@@ -637,9 +1453,8 @@ fn dump_board(board: &[Vec<Unit>], highlight_position: (usize, usize)) {
         }
 
         for col in row {
-            match *col {
-                Goblin(x) | Elf(x) => line.push_str(&format!(" {}", x)),
-                _ => {}
+            if let Fighter { hp, .. } = *col {
+                line.push_str(&format!(" {}", hp));
             }
         }
 
@@ -647,6 +1462,164 @@ fn dump_board(board: &[Vec<Unit>], highlight_position: (usize, usize)) {
     }
 }
 
+fn print_stats(battle: &Battle) {
+    println!("unit  faction   hp  dealt  taken  kills  walked  died");
+    for (id, stats) in &battle.units {
+        println!(
+            "{:>4}  {:<7}  {:>3}  {:>5}  {:>5}  {:>5}  {:>6}  {}",
+            id,
+            stats.faction.to_string(),
+            stats.hp_left,
+            stats.damage_dealt,
+            stats.damage_taken,
+            stats.kills,
+            stats.tiles_walked,
+            stats
+                .died_in_round
+                .map_or("-".to_string(), |round| format!("round {}", round))
+        );
+    }
+
+    println!();
+    for (faction, summary) in battle.factions() {
+        println!(
+            "{}: {} of {} left with {} hp; dealt {}, took {}, killed {}, walked {}",
+            faction,
+            summary.survivors,
+            summary.units,
+            summary.hp_left,
+            summary.damage_dealt,
+            summary.damage_taken,
+            summary.kills,
+            summary.tiles_walked
+        );
+    }
+    println!(
+        "Combat {} after {} full rounds, with an outcome of {}",
+        if battle.timed_out {
+            "is cut short"
+        } else {
+            "ends"
+        },
+        battle.rounds,
+        battle.outcome
+    );
+}
+
+const RULES_USAGE: &str = "\
+rules: --hp <letter>=<hp>  --attack <letter>=<power>  --tiebreak fewest-hp|most-hp|reading-order
+       --diagonal  --max-rounds <rounds>  --ally <letters>, e.g. --ally EO";
+
+// Takes the rule flags out of the command line, leaving everything else in order.
+fn rules_from_args(args: &[String]) -> Result<(CombatRules, Vec<String>), String> {
+    let mut rules = CombatRules::default();
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+
+    let faction_number = |flag: &str, value: Option<&String>| -> Result<(Faction, usize), String> {
+        let value = value.ok_or_else(|| format!("{} needs <letter>=<number>", flag))?;
+        let mut parts = value.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(letter), Some(number)) if letter.len() == 1 => {
+                let letter = letter.chars().next().expect("one character");
+                if !letter.is_ascii_uppercase() {
+                    return Err(format!("{:?} is not a faction letter", letter));
+                }
+                let number = number
+                    .parse()
+                    .map_err(|_| format!("{:?} is not an integer", number))?;
+                Ok((Faction(letter), number))
+            }
+            _ => Err(format!("{} needs <letter>=<number>, not {:?}", flag, value)),
+        }
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--hp" => {
+                let (faction, hp) = faction_number("--hp", args.next())?;
+                rules.starting_hp.insert(faction, hp);
+            }
+            "--attack" => {
+                let (faction, power) = faction_number("--attack", args.next())?;
+                rules.attack_power.insert(faction, power);
+            }
+            "--tiebreak" => {
+                rules.tiebreak = match args.next().map(String::as_str) {
+                    Some("fewest-hp") => Tiebreak::FewestHp,
+                    Some("most-hp") => Tiebreak::MostHp,
+                    Some("reading-order") => Tiebreak::ReadingOrder,
+                    other => return Err(format!("unknown tiebreak {:?}", other)),
+                }
+            }
+            "--diagonal" => rules.diagonal = true,
+            "--max-rounds" => {
+                let rounds = args.next().ok_or("--max-rounds needs a number")?;
+                rules.max_rounds = Some(
+                    rounds
+                        .parse()
+                        .map_err(|_| format!("{:?} is not an integer", rounds))?,
+                );
+            }
+            "--ally" => {
+                let letters = args
+                    .next()
+                    .ok_or("--ally needs the letters of its factions")?;
+                if letters.is_empty() || !letters.chars().all(|c| c.is_ascii_uppercase()) {
+                    return Err(format!("{:?} is not a list of faction letters", letters));
+                }
+                rules.alliances.push(letters.chars().map(Faction).collect());
+            }
+            _ => rest.push(arg.clone()),
+        }
+    }
+    Ok((rules, rest))
+}
+
+#[test]
+fn test_rules_from_args() {
+    let args = |line: &str| {
+        line.split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+
+    let (rules, rest) = rules_from_args(&args("--stats 10")).unwrap();
+    assert_eq!(rules, CombatRules::default());
+    assert_eq!(rest, args("--stats 10"));
+
+    let (rules, rest) = rules_from_args(&args(
+        "--events --hp E=300 --attack G=5 --tiebreak most-hp --diagonal --max-rounds 20",
+    ))
+    .unwrap();
+    assert_eq!(rest, args("--events"));
+    assert_eq!(rules.starting_hp(Faction::ELVES), 300);
+    assert_eq!(rules.starting_hp(Faction::GOBLINS), STARTING_HP);
+    assert_eq!(rules.attack_power(Faction::GOBLINS), 5);
+    assert_eq!(rules.tiebreak, Tiebreak::MostHp);
+    assert!(rules.diagonal);
+    assert_eq!(rules.max_rounds, Some(20));
+
+    let (rules, _) = rules_from_args(&args("--ally EO --ally GX")).unwrap();
+    assert!(!rules.hostile(Faction::ELVES, Faction('O')));
+    assert!(!rules.hostile(Faction('X'), Faction::GOBLINS));
+    assert!(rules.hostile(Faction::ELVES, Faction::GOBLINS));
+
+    for bad in &[
+        "--hp",
+        "--hp E",
+        "--hp e=3",
+        "--attack G=x",
+        "--tiebreak",
+        "--tiebreak weakest",
+        "--max-rounds",
+        "--ally",
+        "--ally Eo",
+    ] {
+        assert!(rules_from_args(&args(bad)).is_err(), "{:?}", bad);
+    }
+}
+
 fn main() {
     env_logger::Builder::from_default_env()
         .default_format_timestamp(false)
@@ -659,10 +1632,72 @@ fn main() {
         .expect("stdin read failed");
 
     let board = crate::board(CompleteByteSlice(&buf)).unwrap().1;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (mut rules, args) = rules_from_args(&args).unwrap_or_else(|error| {
+        eprintln!("{}\n{}", error, RULES_USAGE);
+        std::process::exit(2);
+    });
+    match args.first().map(String::as_str) {
+        // day_15 --events [elf attack power] [rules] < map > log.jsonl
+        // day_15 --stats [elf attack power] [rules] < map
+        Some(mode @ "--events") | Some(mode @ "--stats") => {
+            if let Some(power) = args.get(1) {
+                let power = power.parse().expect("attack power must be an integer");
+                rules.attack_power.insert(Faction::ELVES, power);
+            }
+            if mode == "--events" {
+                run_with_attack_power(board, &rules, |event| println!("{}", event.to_json()));
+            } else {
+                let battle = run_with_attack_power(board, &rules, |_| {})
+                    .expect("the battle has no losses to avoid");
+                print_stats(&battle);
+            }
+            return;
+        }
+        // day_15 --replay <log.jsonl> <rounds> [rules] < map
+        Some("--replay") => {
+            let (log, rounds) = match &args[1..] {
+                [log, rounds] => (log, rounds.parse().expect("rounds must be an integer")),
+                _ => {
+                    eprintln!("usage: day_15 --replay <log.jsonl> <rounds> [rules] < map");
+                    std::process::exit(2);
+                }
+            };
+            let log = std::fs::read_to_string(log).expect("could not read the log");
+            let replayed = log
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .enumerate()
+                .map(|(i, line)| {
+                    Event::from_json(line).map_err(|e| format!("line {}: {}", i + 1, e))
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .and_then(|events| events::replay(board, &rules, &events, rounds));
+            match replayed {
+                Ok(board) => print!("{}", events::render(&board)),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        _ if rules != CombatRules::default() => {
+            eprintln!("the rules only apply to --events, --stats and --replay");
+            std::process::exit(2);
+        }
+        _ => {}
+    }
+
     println!("Outcome of combat is: {}", run(board.clone()));
 
+    #[cfg(not(feature = "rayon"))]
+    let flawless = run_without_killing_elf(&board);
+    #[cfg(feature = "rayon")]
+    let flawless = sweep_without_killing_elf(&board);
     println!(
-        "Outcome of combat with no Elf deaths: {}",
-        run_without_killing_elf(board)
+        "Outcome of combat with no Elf deaths: {} (elf attack power {}, found in {} simulations)",
+        flawless.outcome, flawless.elf_attack_power, flawless.simulations
     )
 }
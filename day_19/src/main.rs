@@ -1,123 +1,17 @@
 use std::io::Read;
 
-use opcodes::Reg;
-
-#[derive(Debug, Eq, PartialEq)]
-struct Opcode(opcodes::Opcode, usize, usize, usize);
-
-mod parser {
-    use nom::branch::alt;
-    use nom::bytes::complete::tag;
-    use nom::character::complete::{digit1, line_ending, space1};
-    use nom::multi::separated_list1;
-    use nom::sequence::tuple;
-    use nom::IResult;
-    use nom::Parser;
-
-    use crate::Opcode;
-
-    fn opcode(input: &str) -> IResult<&str, opcodes::Opcode> {
-        alt((
-            tag("addr").map(|_| opcodes::Opcode::Addr),
-            tag("addi").map(|_| opcodes::Opcode::Addi),
-            tag("mulr").map(|_| opcodes::Opcode::Mulr),
-            tag("muli").map(|_| opcodes::Opcode::Muli),
-            tag("banr").map(|_| opcodes::Opcode::Banr),
-            tag("bani").map(|_| opcodes::Opcode::Bani),
-            tag("borr").map(|_| opcodes::Opcode::Borr),
-            tag("bori").map(|_| opcodes::Opcode::Bori),
-            tag("setr").map(|_| opcodes::Opcode::Setr),
-            tag("seti").map(|_| opcodes::Opcode::Seti),
-            tag("gtir").map(|_| opcodes::Opcode::Gtir),
-            tag("gtri").map(|_| opcodes::Opcode::Gtri),
-            tag("gtrr").map(|_| opcodes::Opcode::Gtrr),
-            tag("eqir").map(|_| opcodes::Opcode::Eqir),
-            tag("eqri").map(|_| opcodes::Opcode::Eqri),
-            tag("eqrr").map(|_| opcodes::Opcode::Eqrr),
-        ))(input)
-    }
-
-    fn integer(input: &str) -> IResult<&str, usize> {
-        digit1
-            .map(|s: &str| s.parse::<usize>().expect("could not parse integer"))
-            .parse(input)
-    }
-
-    fn line(input: &str) -> IResult<&str, Opcode> {
-        tuple((opcode, space1, integer, space1, integer, space1, integer))
-            .map(|(op, _, a, _, b, _, c)| Opcode(op, a, b, c))
-            .parse(input)
-    }
-
-    pub(crate) fn top(input: &str) -> IResult<&str, (usize, Vec<Opcode>)> {
-        tuple((
-            tag("#ip "),
-            integer,
-            line_ending,
-            separated_list1(line_ending, line),
-        ))
-        .map(|(_, binding, _, opcodes)| (binding, opcodes))
-        .parse(input)
-    }
-
-    #[cfg(test)]
-    mod test {
-        #[test]
-        fn top() {
-            use crate::Opcode;
-            use opcodes::Opcode::*;
-
-            let result = super::top(
-                "#ip 0
-seti 5 0 1
-seti 6 0 2
-addi 0 1 0
-addr 1 2 3
-setr 1 0 0
-seti 8 0 4
-seti 9 0 5
-",
-            )
-            .expect("parsing failed")
-            .1;
-
-            assert_eq!(
-                result,
-                (
-                    0,
-                    vec![
-                        Opcode(Seti, 5, 0, 1),
-                        Opcode(Seti, 6, 0, 2),
-                        Opcode(Addi, 0, 1, 0),
-                        Opcode(Addr, 1, 2, 3),
-                        Opcode(Setr, 1, 0, 0),
-                        Opcode(Seti, 8, 0, 4),
-                        Opcode(Seti, 9, 0, 5),
-                    ]
-                )
-            );
-        }
-    }
-}
-
-fn eval(binding: usize, opcodes: Vec<Opcode>) -> [Reg; 6] {
-    let mut regs = [0; 6];
-
-    while (0..opcodes.len()).contains(&(regs[binding] as usize)) {
-        let ip = regs[binding] as usize;
-        let Opcode(opcode, a, b, c) = opcodes[ip];
-
-        regs = opcodes::eval_one(opcode, regs, [0, a, b, c]);
-
-        regs[binding] += 1;
-    }
-
-    // the last instruction executed would have had an out-of-bounds instruction pointer, but that
-    // value would have not been written into the registers (since that happens "during" opcode
-    // execution).  Undo the last += 1, then.
-    regs[binding] -= 1;
-
-    regs
+use opcodes::disasm::Disassembler;
+use opcodes::{Arithmetic, Fault, Instruction, Machine, Program, Reg};
+
+fn eval(
+    binding: usize,
+    opcodes: Vec<Instruction>,
+    arithmetic: Arithmetic,
+) -> Result<[Reg; 6], Fault> {
+    let mut machine: Machine = Machine::try_new(opcodes, Some(binding))?;
+    machine.arithmetic = arithmetic;
+    machine.run()?;
+    Ok(machine.registers)
 }
 
 #[cfg(test)]
@@ -131,18 +25,92 @@ mod test {
             super::eval(
                 0,
                 vec![
-                    Opcode(Seti, 5, 0, 1),
-                    Opcode(Seti, 6, 0, 2),
-                    Opcode(Addi, 0, 1, 0),
-                    Opcode(Addr, 1, 2, 3),
-                    Opcode(Setr, 1, 0, 0),
-                    Opcode(Seti, 8, 0, 4),
-                    Opcode(Seti, 9, 0, 5),
-                ]
+                    Instruction(Seti, 5, 0, 1),
+                    Instruction(Seti, 6, 0, 2),
+                    Instruction(Addi, 0, 1, 0),
+                    Instruction(Addr, 1, 2, 3),
+                    Instruction(Setr, 1, 0, 0),
+                    Instruction(Seti, 8, 0, 4),
+                    Instruction(Seti, 9, 0, 5),
+                ],
+                Arithmetic::Checked,
             ),
-            [6, 5, 6, 0, 0, 9]
+            Ok([6, 5, 6, 0, 0, 9])
         );
     }
+
+    #[test]
+    fn eval_fault() {
+        assert_eq!(
+            super::eval(
+                0,
+                vec![
+                    Instruction(Seti, 5, 0, 1),
+                    Instruction(Addr, 1, 6, 3),
+                    Instruction(Seti, 9, 0, 5),
+                ],
+                Arithmetic::Checked,
+            ),
+            Err(Fault {
+                ip: 1,
+                error: opcodes::Error::RegisterOutOfRange {
+                    opcode: Addr,
+                    operand: opcodes::Operand::B,
+                    index: 6,
+                    width: 6,
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn eval_overflow() {
+        let program = || {
+            vec![
+                Instruction(Seti, Reg::MAX as usize, 0, 1),
+                Instruction(Addi, 1, 1, 2),
+            ]
+        };
+
+        assert_eq!(
+            super::eval(0, program(), Arithmetic::Checked),
+            Err(Fault {
+                ip: 1,
+                error: opcodes::Error::Overflow { opcode: Addi }
+            })
+        );
+        assert_eq!(
+            super::eval(0, program(), Arithmetic::Wrapping),
+            Ok([1, Reg::MAX, 0, 0, 0, 0])
+        );
+        assert_eq!(
+            super::eval(0, program(), Arithmetic::Saturating),
+            Ok([1, Reg::MAX, Reg::MAX, 0, 0, 0])
+        );
+    }
+}
+
+fn or_exit<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    })
+}
+
+fn profile(program: Program, r0: Reg, max_steps: Option<u64>) {
+    let mut machine: Machine = or_exit(Machine::try_new(
+        program.instructions.clone(),
+        program.ip_binding,
+    ));
+    machine.registers[0] = r0;
+    let profile = or_exit(opcodes::profile::run(&mut machine, max_steps));
+
+    print!("{}", profile.annotate(&Disassembler::new(&program)));
+    println!(
+        "\n{} steps, final registers {:?}",
+        machine.steps(),
+        machine.registers
+    );
 }
 
 fn main() {
@@ -152,8 +120,41 @@ fn main() {
         .read_to_string(&mut input)
         .expect("could not read stdin");
 
-    let (binding, opcodes) = parser::top(&input).expect("parsing failed").1;
-    let regs = eval(binding, opcodes);
+    let program = or_exit(opcodes::parse_program(&input));
+
+    // day_19 --profile [r0] [max steps]
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--profile") {
+        let r0 = args
+            .get(1)
+            .map_or(0, |r0| r0.parse().expect("r0 must be an integer"));
+        let max_steps = args
+            .get(2)
+            .map(|steps| steps.parse().expect("max steps must be an integer"));
+        profile(program, r0, max_steps);
+        return;
+    }
+
+    let binding = program.ip_binding.expect("program has no #ip line");
+    let regs = or_exit(eval(
+        binding,
+        program.instructions.clone(),
+        Arithmetic::Checked,
+    ));
     let part1 = regs[0];
     dbg!(part1);
+
+    // Part 2 is the same program with r0 = 1, which makes the number whose divisors it sums far
+    // too large to actually run the loops.
+    let idioms = opcodes::idiom::recognize(&program);
+    let mut machine: Machine = or_exit(Machine::try_new(program.instructions, program.ip_binding));
+    machine.registers[0] = 1;
+    or_exit(opcodes::idiom::run(&mut machine, &idioms, |idiom| {
+        eprintln!(
+            "replaced {:?} loop at {}..{} with its closed form",
+            idiom.kind, idiom.start, idiom.end
+        )
+    }));
+    let part2 = machine.registers[0];
+    dbg!(part2);
 }
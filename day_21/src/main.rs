@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+use std::io::Read;
+
+use opcodes::{Fault, Instruction, Machine, Opcode, Program, Reg, Source};
+
+// The only place the program looks at r0 is a single `eqrr` that halts when r0 equals some
+// register.  Returns the index of that instruction and the register r0 is compared against.
+fn find_check(program: &Program) -> Result<(usize, usize), String> {
+    let mut reads =
+        program
+            .instructions
+            .iter()
+            .enumerate()
+            .filter(|(_, Instruction(opcode, a, b, _))| {
+                let (source_a, source_b) = opcode.sources();
+                (source_a == Source::Register && *a == 0)
+                    || (source_b == Source::Register && *b == 0)
+            });
+
+    let (ip, &Instruction(opcode, a, b, _)) = reads
+        .next()
+        .ok_or_else(|| "the program never reads r0".to_string())?;
+    if let Some((other, _)) = reads.next() {
+        return Err(format!(
+            "r0 is read by both instruction {} and instruction {}",
+            ip, other
+        ));
+    }
+    match (opcode, a, b) {
+        (Opcode::Eqrr, 0, register) | (Opcode::Eqrr, register, 0) if register != 0 => {
+            Ok((ip, register))
+        }
+        _ => Err(format!(
+            "instruction {} reads r0 but is not an eqrr against another register",
+            ip
+        )),
+    }
+}
+
+// The values r0 is compared against, in order, up to the first repeat, each with the number of
+// instructions executed before reaching the comparison.  Once a value repeats the machine's
+// state is back where it was before (r0 aside), so no later comparison can be new.
+fn compared_values(program: Program) -> Result<Vec<(Reg, u64)>, String> {
+    let (check, register) = find_check(&program)?;
+    let mut machine: Machine = Machine::try_new(program.instructions, program.ip_binding)
+        .map_err(|fault| fault.to_string())?;
+    let mut seen = HashSet::new();
+    let mut values = Vec::new();
+
+    loop {
+        machine
+            .run_until(|machine| machine.ip() == check)
+            .map_err(|fault: Fault| fault.to_string())?;
+        if machine.ip() != check {
+            return Err(format!(
+                "program halted after {} steps without reaching the comparison",
+                machine.steps()
+            ));
+        }
+
+        let value = machine.registers[register];
+        if !seen.insert(value) {
+            return Ok(values);
+        }
+        values.push((value, machine.steps()));
+
+        // Keep the comparison failing so the program carries on to the next value.
+        machine.registers[0] = !value;
+        machine.step().map_err(|fault| fault.to_string())?;
+    }
+}
+
+fn or_exit<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    })
+}
+
+fn main() {
+    let mut input = String::new();
+    std::io::stdin()
+        .lock()
+        .read_to_string(&mut input)
+        .expect("could not read stdin");
+
+    let program = or_exit(opcodes::parse_program(&input));
+    let values = or_exit(compared_values(program));
+
+    // Halting after the comparison takes the same couple of instructions whichever value it
+    // was, so the first value seen halts soonest and the last new one halts latest.
+    let (part1, _) = values[0];
+    dbg!(part1);
+    let (part2, steps) = values[values.len() - 1];
+    dbg!(part2, steps);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // r1 walks a full-period linear congruential sequence mod 16, halting when it equals r0.
+    const PROGRAM: &str = "#ip 5
+seti 0 0 1
+muli 1 5 1
+addi 1 3 1
+bani 1 15 1
+eqrr 1 0 2
+addr 2 5 5
+seti 0 0 5
+";
+
+    #[test]
+    fn check() {
+        let program = opcodes::parse_program(PROGRAM).unwrap();
+        assert_eq!(find_check(&program), Ok((4, 1)));
+
+        let program = opcodes::parse_program("#ip 5\naddr 0 0 1\neqrr 1 0 2\n").unwrap();
+        assert_eq!(
+            find_check(&program),
+            Err("r0 is read by both instruction 0 and instruction 1".to_string())
+        );
+    }
+
+    #[test]
+    fn values() {
+        let program = opcodes::parse_program(PROGRAM).unwrap();
+        let values = compared_values(program.clone()).unwrap();
+        assert_eq!(
+            values.iter().map(|&(value, _)| value).collect::<Vec<_>>(),
+            vec![3, 2, 13, 4, 7, 6, 1, 8, 11, 10, 5, 12, 15, 14, 9, 0]
+        );
+
+        // Every value really does halt the program, after the predicted number of steps.
+        for &(value, steps) in &values {
+            let mut machine: Machine = Machine::from(program.clone());
+            machine.registers[0] = value;
+            machine.run().unwrap();
+            assert_eq!(machine.steps(), steps + 2);
+        }
+    }
+}
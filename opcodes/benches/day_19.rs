@@ -0,0 +1,62 @@
+// Compares Machine's step-at-a-time loop with the pre-decoded engine on day 19 part 1.  Run with
+// `cargo bench -p opcodes`; the input is read from inputs/day_19.txt at the workspace root.
+
+use std::time::{Duration, Instant};
+
+use opcodes::decode::Decoded;
+use opcodes::{Arithmetic, Machine, Program, Reg};
+
+const WARM_UP: Duration = Duration::from_secs(1);
+const SAMPLES: usize = 10;
+
+// Times `routine` like criterion would: run it until warmed up, then report the spread over a
+// fixed number of samples.
+fn bench<F: FnMut() -> [Reg; 6]>(name: &str, mut routine: F) -> Duration {
+    let start = Instant::now();
+    while start.elapsed() < WARM_UP {
+        std::hint::black_box(routine());
+    }
+
+    let mut times: Vec<Duration> = (0..SAMPLES)
+        .map(|_| {
+            let start = Instant::now();
+            std::hint::black_box(routine());
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+
+    let median = times[SAMPLES / 2];
+    println!(
+        "{:<10} time: [{:>10.3?} {:>10.3?} {:>10.3?}]",
+        name,
+        times[0],
+        median,
+        times[SAMPLES - 1]
+    );
+    median
+}
+
+fn main() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../inputs/day_19.txt");
+    let input = std::fs::read_to_string(path).expect("could not read inputs/day_19.txt");
+    let program: Program = opcodes::parse_program(&input).expect("could not parse day 19");
+
+    let machine = bench("machine", || {
+        let mut machine: Machine = Machine::from(program.clone());
+        machine.run().unwrap();
+        machine.registers
+    });
+
+    let decoded = bench("decoded", || {
+        let decoded: Decoded = Decoded::new(&program, Arithmetic::Checked).unwrap();
+        let mut registers = [0; 6];
+        decoded.run(&mut registers).unwrap();
+        registers
+    });
+
+    println!(
+        "decoded is {:.1}x faster",
+        machine.as_secs_f64() / decoded.as_secs_f64()
+    );
+}
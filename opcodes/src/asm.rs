@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+
+use crate::{Instruction, Op, Opcode, Program, Source};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AsmError {
+    Syntax { line: usize, expected: &'static str },
+    UnknownMnemonic { line: usize, mnemonic: String },
+    UnknownName { line: usize, name: String },
+    Redefined { line: usize, name: String },
+    // jmp and halt are written in terms of the instruction pointer, so they need an #ip header.
+    Unbound { line: usize },
+    // A jump sets the ip to one less than its target, which is impossible for instruction 0.
+    JumpToStart { line: usize },
+}
+
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AsmError::Syntax { line, expected } => {
+                write!(f, "line {}: expected {}", line, expected)
+            }
+            AsmError::UnknownMnemonic { line, mnemonic } => {
+                write!(f, "line {}: unknown instruction {}", line, mnemonic)
+            }
+            AsmError::UnknownName { line, name } => {
+                write!(f, "line {}: unknown name {}", line, name)
+            }
+            AsmError::Redefined { line, name } => {
+                write!(f, "line {}: {} is already defined", line, name)
+            }
+            AsmError::Unbound { line } => write!(
+                f,
+                "line {}: jmp and halt need the instruction pointer bound with #ip",
+                line
+            ),
+            AsmError::JumpToStart { line } => {
+                write!(f, "line {}: cannot jump to the first instruction", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Mnemonic {
+    Opcode(Opcode),
+    Jmp,
+    Halt,
+}
+
+// One source line that produces an instruction, with its operands still unresolved.
+struct Pending<'a> {
+    line: usize,
+    mnemonic: Mnemonic,
+    operands: Vec<&'a str>,
+}
+
+fn is_name(token: &str) -> bool {
+    let mut chars = token.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Everything the second pass needs to turn names into numbers.
+struct Symbols<'a> {
+    ip_binding: Option<usize>,
+    labels: HashMap<&'a str, usize>,
+    registers: HashMap<&'a str, usize>,
+}
+
+impl<'a> Symbols<'a> {
+    // r3, an alias from .reg, "ip" for the bound register, or a bare number as in plain elfcode.
+    fn register(&self, line: usize, token: &str) -> Result<Op, AsmError> {
+        if let Ok(index) = token.parse() {
+            return Ok(index);
+        }
+        if let Some(&index) = self.registers.get(token) {
+            return Ok(index);
+        }
+        if token == "ip" {
+            return self.ip_binding.ok_or(AsmError::Unbound { line });
+        }
+        match token.strip_prefix('r').map(str::parse) {
+            Some(Ok(index)) => Ok(index),
+            _ if is_name(token) => Err(AsmError::UnknownName {
+                line,
+                name: token.to_string(),
+            }),
+            _ => Err(AsmError::Syntax {
+                line,
+                expected: "a register",
+            }),
+        }
+    }
+
+    // An integer, or a label standing for the index of the instruction it marks.
+    fn immediate(&self, line: usize, token: &str) -> Result<Op, AsmError> {
+        if let Ok(value) = token.parse() {
+            return Ok(value);
+        }
+        match self.labels.get(token) {
+            Some(&index) => Ok(index),
+            None if is_name(token) => Err(AsmError::UnknownName {
+                line,
+                name: token.to_string(),
+            }),
+            None => Err(AsmError::Syntax {
+                line,
+                expected: "an integer or a label",
+            }),
+        }
+    }
+
+    fn lower(&self, pending: &Pending, len: usize) -> Result<Instruction, AsmError> {
+        let line = pending.line;
+        let operands = &pending.operands;
+        let arity = |expected| {
+            if operands.len() == expected {
+                Ok(())
+            } else {
+                Err(AsmError::Syntax {
+                    line,
+                    expected: match expected {
+                        0 => "no operands",
+                        1 => "one operand",
+                        _ => "three operands",
+                    },
+                })
+            }
+        };
+
+        match pending.mnemonic {
+            Mnemonic::Jmp => {
+                arity(1)?;
+                let ip = self.ip_binding.ok_or(AsmError::Unbound { line })?;
+                let target = self.immediate(line, operands[0])?;
+                let before = target
+                    .checked_sub(1)
+                    .ok_or(AsmError::JumpToStart { line })?;
+                Ok(Instruction(Opcode::Seti, before, 0, ip))
+            }
+            Mnemonic::Halt => {
+                arity(0)?;
+                let ip = self.ip_binding.ok_or(AsmError::Unbound { line })?;
+                Ok(Instruction(Opcode::Seti, len, 0, ip))
+            }
+            Mnemonic::Opcode(opcode) => {
+                // setr and seti ignore their second operand, so it may be left out.
+                let operands: Vec<&str> = match (opcode, operands.len()) {
+                    (Opcode::Setr, 2) | (Opcode::Seti, 2) => vec![operands[0], "0", operands[1]],
+                    _ => {
+                        arity(3)?;
+                        operands.clone()
+                    }
+                };
+
+                let (source_a, source_b) = opcode.sources();
+                let operand = |source, token| match source {
+                    Source::Register => self.register(line, token),
+                    Source::Immediate => self.immediate(line, token),
+                    Source::Ignored => token.parse().map_err(|_| AsmError::Syntax {
+                        line,
+                        expected: "an integer",
+                    }),
+                };
+                Ok(Instruction(
+                    opcode,
+                    operand(source_a, operands[0])?,
+                    operand(source_b, operands[1])?,
+                    self.register(line, operands[2])?,
+                ))
+            }
+        }
+    }
+}
+
+// Assembles a listing into a plain Program, whose Display is the "#ip" format that
+// parse_program reads back.  On top of that format, the source may use:
+//
+//   # comments, anywhere except an "#ip" header
+//   loop:                  labels, on their own line or before an instruction
+//   .reg counter r3        register aliases; "ip" always names the bound register
+//   jmp loop / halt        pseudo-instructions that write the instruction pointer
+//   seti 5 counter         setr/seti without their ignored operand
+//
+// Labels may be used wherever an immediate is expected, and stand for an instruction index.
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    let mut symbols = Symbols {
+        ip_binding: None,
+        labels: HashMap::new(),
+        registers: HashMap::new(),
+    };
+    let mut pending = Vec::new();
+
+    for (line, text) in source.lines().enumerate().map(|(i, l)| (i + 1, l)) {
+        let text = text.trim();
+        if let Some(binding) = text.strip_prefix("#ip") {
+            if !pending.is_empty() || symbols.ip_binding.is_some() {
+                return Err(AsmError::Syntax {
+                    line,
+                    expected: "a single #ip header before any instruction",
+                });
+            }
+            let binding = binding.split('#').next().unwrap_or("").trim();
+            symbols.ip_binding = Some(symbols.register(line, binding)?);
+            continue;
+        }
+
+        let code = text.split('#').next().unwrap_or("");
+        let mut tokens: Vec<&str> = code.split_whitespace().collect();
+
+        while let Some(label) = tokens.first().and_then(|token| token.strip_suffix(':')) {
+            if !is_name(label) {
+                return Err(AsmError::Syntax {
+                    line,
+                    expected: "a label name",
+                });
+            }
+            if symbols.labels.insert(label, pending.len()).is_some() {
+                return Err(AsmError::Redefined {
+                    line,
+                    name: label.to_string(),
+                });
+            }
+            tokens.remove(0);
+        }
+
+        let (&first, operands) = match tokens.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+
+        if first == ".reg" {
+            match *operands {
+                [alias, register] if is_name(alias) && alias != "ip" => {
+                    let index = symbols.register(line, register)?;
+                    if symbols.registers.insert(alias, index).is_some() {
+                        return Err(AsmError::Redefined {
+                            line,
+                            name: alias.to_string(),
+                        });
+                    }
+                }
+                _ => {
+                    return Err(AsmError::Syntax {
+                        line,
+                        expected: "an alias and a register after .reg",
+                    })
+                }
+            }
+            continue;
+        }
+
+        let mnemonic = match first {
+            "jmp" => Mnemonic::Jmp,
+            "halt" => Mnemonic::Halt,
+            _ => Mnemonic::Opcode(first.parse().map_err(|_| AsmError::UnknownMnemonic {
+                line,
+                mnemonic: first.to_string(),
+            })?),
+        };
+        pending.push(Pending {
+            line,
+            mnemonic,
+            operands: operands.to_vec(),
+        });
+    }
+
+    let len = pending.len();
+    let instructions = pending
+        .iter()
+        .map(|pending| symbols.lower(pending, len))
+        .collect::<Result<_, _>>()?;
+
+    Ok(Program {
+        ip_binding: symbols.ip_binding,
+        instructions,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Machine;
+
+    #[test]
+    fn lowering() {
+        let program = assemble(
+            "# counts r1 up to r0
+#ip 5
+.reg limit r0
+.reg counter r1
+.reg flag 2
+
+        seti 0 counter      # start from zero
+loop:   addi counter 1 counter
+        gtrr counter limit flag
+        addr flag ip ip
+        jmp loop
+done:   halt
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            program.to_string(),
+            "#ip 5
+seti 0 0 1
+addi 1 1 1
+gtrr 1 0 2
+addr 2 5 5
+seti 0 0 5
+seti 6 0 5
+"
+        );
+        assert_eq!(
+            crate::parse_program(&program.to_string()),
+            Ok(program.clone())
+        );
+
+        let mut machine: Machine = Machine::from(program);
+        machine.registers[0] = 7;
+        machine.run().unwrap();
+        assert_eq!(machine.registers[1], 8);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            assemble("#ip 1\nseti 1 0 r2\njmp nowhere\n"),
+            Err(AsmError::UnknownName {
+                line: 3,
+                name: "nowhere".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("start: seti 1 0 r2\njmp start\n"),
+            Err(AsmError::Unbound { line: 2 })
+        );
+        assert_eq!(
+            assemble("#ip 0\nstart: seti 1 0 r2\njmp start\n"),
+            Err(AsmError::JumpToStart { line: 3 })
+        );
+        assert_eq!(
+            assemble("a: seti 1 0 1\na: seti 1 0 1\n"),
+            Err(AsmError::Redefined {
+                line: 2,
+                name: "a".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("movr 1 2 3\n"),
+            Err(AsmError::UnknownMnemonic {
+                line: 1,
+                mnemonic: "movr".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("addr 1 2\n"),
+            Err(AsmError::Syntax {
+                line: 1,
+                expected: "three operands"
+            })
+        );
+    }
+}
@@ -0,0 +1,18 @@
+use std::io::Read;
+
+// Reads assembler source on stdin and prints the plain "#ip" listing that day 19 expects.
+fn main() {
+    let mut input = String::new();
+    std::io::stdin()
+        .lock()
+        .read_to_string(&mut input)
+        .expect("could not read stdin");
+
+    match opcodes::asm::assemble(&input) {
+        Ok(program) => print!("{}", program),
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Write};
+
+use opcodes::disasm::Disassembler;
+use opcodes::trace::Tracer;
+use opcodes::{Fault, Machine, Program, Reg, Status};
+
+// how many steps of history to keep for stepping backwards
+const HISTORY: usize = 1_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Value {
+    Register(usize),
+    Constant(Reg),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Condition(Value, Comparison, Value);
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Register(register) => write!(f, "r{}", register),
+            Value::Constant(constant) => write!(f, "{}", constant),
+        }
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let Condition(a, comparison, b) = self;
+        let comparison = match comparison {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        };
+        write!(f, "{} {} {}", a, comparison, b)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Step(u64),
+    Back(u64),
+    Last(usize),
+    Export(String),
+    Continue,
+    Break(usize, Option<Condition>),
+    Delete(usize),
+    Watch(usize),
+    Unwatch(usize),
+    Set(usize, Reg),
+    Info,
+    List,
+    Help,
+    Quit,
+}
+
+const HELP: &str = "\
+step [n]                    execute n instructions (default 1)
+back [n]                    undo n instructions (default 1)
+last <reg>                  go back to just before <reg> last changed
+export <file>               write the remembered history as .csv or .jsonl
+continue                    run until a breakpoint, a watch, or the program halts
+break <ip> [if <a> <op> <b>]  stop before executing instruction <ip>
+delete <ip>                 remove a breakpoint
+watch <reg>                 stop whenever <reg> changes
+unwatch <reg>               stop watching <reg>
+set <reg> <value>           change a register (forgets the history)
+info                        show registers, breakpoints and watches
+list                        disassemble the whole program
+quit
+Operands of a condition are registers (r0..r5) or integers; <op> is one of == != < <= > >=.
+";
+
+fn register(token: &str) -> Result<usize, String> {
+    token
+        .strip_prefix('r')
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n < 6)
+        .ok_or_else(|| format!("{:?} is not a register", token))
+}
+
+fn integer<T: std::str::FromStr>(token: &str) -> Result<T, String> {
+    token
+        .parse()
+        .map_err(|_| format!("{:?} is not an integer", token))
+}
+
+fn value(token: &str) -> Result<Value, String> {
+    if token.starts_with('r') {
+        register(token).map(Value::Register)
+    } else {
+        integer(token).map(Value::Constant)
+    }
+}
+
+fn comparison(token: &str) -> Result<Comparison, String> {
+    Ok(match token {
+        "==" => Comparison::Eq,
+        "!=" => Comparison::Ne,
+        "<" => Comparison::Lt,
+        "<=" => Comparison::Le,
+        ">" => Comparison::Gt,
+        ">=" => Comparison::Ge,
+        _ => return Err(format!("{:?} is not a comparison", token)),
+    })
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let arguments = |count: usize| {
+        if tokens.len() == count + 1 {
+            Ok(())
+        } else {
+            Err(format!("{} takes {} argument(s)", tokens[0], count))
+        }
+    };
+
+    match tokens.first().cloned().unwrap_or("") {
+        "step" | "s" => match tokens.get(1) {
+            Some(n) => arguments(1).and(integer(n).map(Command::Step)),
+            None => Ok(Command::Step(1)),
+        },
+        "back" => match tokens.get(1) {
+            Some(n) => arguments(1).and(integer(n).map(Command::Back)),
+            None => Ok(Command::Back(1)),
+        },
+        "last" => arguments(1).and_then(|_| register(tokens[1]).map(Command::Last)),
+        "export" => arguments(1).map(|_| Command::Export(tokens[1].to_string())),
+        "continue" | "c" => arguments(0).map(|_| Command::Continue),
+        "break" | "b" => match tokens[1..] {
+            [ip] => Ok(Command::Break(integer(ip)?, None)),
+            [ip, "if", a, op, b] => Ok(Command::Break(
+                integer(ip)?,
+                Some(Condition(value(a)?, comparison(op)?, value(b)?)),
+            )),
+            _ => Err("usage: break <ip> [if <a> <op> <b>]".to_string()),
+        },
+        "delete" | "d" => arguments(1).and_then(|_| integer(tokens[1]).map(Command::Delete)),
+        "watch" | "w" => arguments(1).and_then(|_| register(tokens[1]).map(Command::Watch)),
+        "unwatch" => arguments(1).and_then(|_| register(tokens[1]).map(Command::Unwatch)),
+        "set" => {
+            arguments(2).and_then(|_| Ok(Command::Set(register(tokens[1])?, integer(tokens[2])?)))
+        }
+        "info" | "i" => arguments(0).map(|_| Command::Info),
+        "list" | "l" => arguments(0).map(|_| Command::List),
+        "help" | "h" => Ok(Command::Help),
+        "quit" | "q" => Ok(Command::Quit),
+        other => Err(format!("unknown command {:?}; try help", other)),
+    }
+}
+
+struct Debugger {
+    program: Program,
+    tracer: Tracer,
+    breakpoints: BTreeMap<usize, Option<Condition>>,
+    watches: BTreeSet<usize>,
+}
+
+impl Debugger {
+    fn new(program: Program) -> Result<Self, Fault> {
+        let machine = Machine::try_new(program.instructions.clone(), program.ip_binding)?;
+        Ok(Debugger {
+            tracer: Tracer::new(machine, Some(HISTORY)),
+            program,
+            breakpoints: BTreeMap::new(),
+            watches: BTreeSet::new(),
+        })
+    }
+
+    fn get(&self, value: Value) -> Reg {
+        match value {
+            Value::Register(register) => self.tracer.machine.registers[register],
+            Value::Constant(constant) => constant,
+        }
+    }
+
+    fn holds(&self, Condition(a, comparison, b): Condition) -> bool {
+        let (a, b) = (self.get(a), self.get(b));
+        match comparison {
+            Comparison::Eq => a == b,
+            Comparison::Ne => a != b,
+            Comparison::Lt => a < b,
+            Comparison::Le => a <= b,
+            Comparison::Gt => a > b,
+            Comparison::Ge => a >= b,
+        }
+    }
+
+    fn location(&self) -> String {
+        let ip = self.tracer.machine.ip();
+        let current = if ip < self.program.instructions.len() {
+            Disassembler::new(&self.program).line(ip)
+        } else {
+            "(halted)".to_string()
+        };
+        format!(
+            "{:>3}: {}\n     {:?} after {} steps\n",
+            ip,
+            current,
+            self.tracer.machine.registers,
+            self.tracer.machine.steps()
+        )
+    }
+
+    // Runs for up to `limit` instructions, stopping early at breakpoints and watches.  The
+    // instruction under the cursor is always executed, so continuing from a breakpoint works.
+    fn resume(&mut self, limit: Option<u64>) -> String {
+        let mut reason = String::new();
+
+        for _ in 0..limit.unwrap_or(u64::MAX) {
+            let before = self.tracer.machine.registers;
+            match self.tracer.step() {
+                Err(fault) => {
+                    reason = format!("{}\n", fault);
+                    break;
+                }
+                Ok(Status::Halted) => {
+                    reason = "program halted\n".to_string();
+                    break;
+                }
+                Ok(Status::Running) => {}
+            }
+
+            for &register in &self.watches {
+                let after = self.tracer.machine.registers[register];
+                if before[register] != after {
+                    reason.push_str(&format!(
+                        "r{} changed: {} -> {}\n",
+                        register, before[register], after
+                    ));
+                }
+            }
+
+            let ip = self.tracer.machine.ip();
+            match self.breakpoints.get(&ip) {
+                Some(None) => reason.push_str(&format!("breakpoint at {}\n", ip)),
+                Some(&Some(condition)) if self.holds(condition) => {
+                    reason.push_str(&format!("conditional breakpoint at {}\n", ip))
+                }
+                _ => {}
+            }
+
+            if !reason.is_empty() {
+                break;
+            }
+        }
+
+        reason + &self.location()
+    }
+
+    fn execute(&mut self, command: Command) -> String {
+        match command {
+            Command::Step(n) => self.resume(Some(n)),
+            Command::Back(n) => {
+                let undone = (0..n)
+                    .take_while(|_| self.tracer.step_back().is_some())
+                    .count();
+                let mut output = String::new();
+                if (undone as u64) < n {
+                    output.push_str("reached the start of the history\n");
+                }
+                output + &self.location()
+            }
+            Command::Last(register) => match self.tracer.back_to_change(register) {
+                Some(change) => format!(
+                    "r{} is about to change: {} -> {}\n{}",
+                    register,
+                    change.before,
+                    change.after,
+                    self.location()
+                ),
+                None => format!("r{} has not changed in the remembered history\n", register),
+            },
+            Command::Export(path) => {
+                let result = std::fs::File::create(&path).and_then(|file| {
+                    let out = std::io::BufWriter::new(file);
+                    if path.ends_with(".jsonl") {
+                        self.tracer.write_jsonl(out)
+                    } else {
+                        self.tracer.write_csv(out)
+                    }
+                });
+                match result {
+                    Ok(()) => format!("wrote {} steps to {}\n", self.tracer.len(), path),
+                    Err(error) => format!("could not write {}: {}\n", path, error),
+                }
+            }
+            Command::Continue => self.resume(None),
+            Command::Break(ip, condition) => {
+                self.breakpoints.insert(ip, condition);
+                format!("breakpoint set at {}\n", ip)
+            }
+            Command::Delete(ip) => match self.breakpoints.remove(&ip) {
+                Some(_) => format!("breakpoint at {} deleted\n", ip),
+                None => format!("no breakpoint at {}\n", ip),
+            },
+            Command::Watch(register) => {
+                self.watches.insert(register);
+                format!("watching r{}\n", register)
+            }
+            Command::Unwatch(register) => {
+                self.watches.remove(&register);
+                format!("no longer watching r{}\n", register)
+            }
+            Command::Set(register, value) => {
+                self.tracer.machine.registers[register] = value;
+                self.tracer.clear();
+                self.location()
+            }
+            Command::Info => {
+                let mut info = self.location();
+                for (ip, condition) in &self.breakpoints {
+                    match condition {
+                        Some(condition) => {
+                            info.push_str(&format!("break {} if {}\n", ip, condition))
+                        }
+                        None => info.push_str(&format!("break {}\n", ip)),
+                    }
+                }
+                for register in &self.watches {
+                    info.push_str(&format!("watch r{}\n", register));
+                }
+                info
+            }
+            Command::List => Disassembler::new(&self.program).listing(),
+            Command::Help => HELP.to_string(),
+            Command::Quit => String::new(),
+        }
+    }
+}
+
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .expect("usage: elfcode-debug <program file>");
+    let input = std::fs::read_to_string(&path).expect("could not read program");
+    let program = opcodes::parse_program(&input).unwrap_or_else(|error| {
+        eprintln!("{}: {}", path, error);
+        std::process::exit(1);
+    });
+
+    let mut debugger = Debugger::new(program).unwrap_or_else(|fault| {
+        eprintln!("{}: {}", path, fault.error);
+        std::process::exit(1);
+    });
+    print!("{}", debugger.location());
+
+    let stdin = std::io::stdin();
+    let mut previous = String::new();
+    loop {
+        print!("(elf) ");
+        std::io::stdout().flush().expect("stdout write failed");
+
+        let mut line = String::new();
+        if stdin
+            .lock()
+            .read_line(&mut line)
+            .expect("stdin read failed")
+            == 0
+        {
+            break;
+        }
+        // like gdb, an empty line repeats the last command
+        if line.trim().is_empty() {
+            line = previous.clone();
+        }
+
+        match parse_command(&line) {
+            Ok(Command::Quit) => break,
+            Ok(command) => print!("{}", debugger.execute(command)),
+            Err(message) => println!("{}", message),
+        }
+        previous = line;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse_command("step"), Ok(Command::Step(1)));
+        assert_eq!(parse_command("s 10\n"), Ok(Command::Step(10)));
+        assert_eq!(
+            parse_command("break 7 if r2 == r5"),
+            Ok(Command::Break(
+                7,
+                Some(Condition(
+                    Value::Register(2),
+                    Comparison::Eq,
+                    Value::Register(5)
+                ))
+            ))
+        );
+        assert_eq!(parse_command("watch r3"), Ok(Command::Watch(3)));
+        assert_eq!(parse_command("set r0 1"), Ok(Command::Set(0, 1)));
+        assert_eq!(parse_command("back"), Ok(Command::Back(1)));
+        assert_eq!(parse_command("last r0"), Ok(Command::Last(0)));
+        assert_eq!(
+            parse_command("export trace.csv"),
+            Ok(Command::Export("trace.csv".to_string()))
+        );
+        assert!(parse_command("set r9 1").is_err());
+        assert!(parse_command("break 7 if r2 ~ r5").is_err());
+        assert!(parse_command("frobnicate").is_err());
+    }
+
+    #[test]
+    fn missing_arguments() {
+        for line in &["delete", "watch", "unwatch", "set", "set r0", "last"] {
+            assert!(parse_command(line).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn session() {
+        let program = opcodes::parse_program(
+            "#ip 0
+seti 0 0 1
+addi 1 1 1
+gtri 1 2 2
+addr 0 2 0
+seti 0 0 0
+",
+        )
+        .unwrap();
+        let mut debugger = Debugger::new(program).unwrap();
+
+        debugger.execute(Command::Break(
+            1,
+            Some(Condition(
+                Value::Register(1),
+                Comparison::Eq,
+                Value::Constant(2),
+            )),
+        ));
+        let output = debugger.execute(Command::Continue);
+        assert!(output.starts_with("conditional breakpoint at 1\n  1: r1 = r1 + 1\n"));
+        assert_eq!(debugger.tracer.machine.registers[1], 2);
+
+        debugger.execute(Command::Watch(2));
+        let output = debugger.execute(Command::Continue);
+        assert!(output.starts_with("r2 changed: 0 -> 1\n"));
+
+        let output = debugger.execute(Command::Last(1));
+        assert!(output.starts_with("r1 is about to change: 2 -> 3\n  1: r1 = r1 + 1\n"));
+        debugger.execute(Command::Back(2));
+        assert_eq!(debugger.tracer.machine.ip(), 3);
+
+        debugger.execute(Command::Unwatch(2));
+        debugger.execute(Command::Set(1, 5));
+        let output = debugger.execute(Command::Step(100));
+        assert!(output.starts_with("program halted\n"));
+        assert_eq!(debugger.tracer.machine.status(), Status::Halted);
+    }
+}
@@ -0,0 +1,19 @@
+use std::io::Read;
+
+use opcodes::disasm::Disassembler;
+use opcodes::flow::Graph;
+
+fn main() {
+    let mut input = String::new();
+    std::io::stdin()
+        .lock()
+        .read_to_string(&mut input)
+        .expect("could not read stdin");
+
+    let program = opcodes::parse_program(&input).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    });
+
+    print!("{}", Graph::new(&program).dot(&Disassembler::new(&program)));
+}
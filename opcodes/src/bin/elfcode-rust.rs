@@ -0,0 +1,34 @@
+use std::io::Read;
+
+use opcodes::transpile::{transpile, Style};
+
+// elfcode-rust [--per-instruction] < program > program.rs
+fn main() {
+    let style = match std::env::args().nth(1).as_deref() {
+        None => Style::Blocks,
+        Some("--per-instruction") => Style::PerInstruction,
+        Some(other) => {
+            eprintln!("unknown option {}", other);
+            std::process::exit(2);
+        }
+    };
+
+    let mut input = String::new();
+    std::io::stdin()
+        .lock()
+        .read_to_string(&mut input)
+        .expect("could not read stdin");
+
+    let program = opcodes::parse_program(&input).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    });
+
+    match transpile(&program, style) {
+        Ok(source) => print!("{}", source),
+        Err(fault) => {
+            eprintln!("{}", fault);
+            std::process::exit(1);
+        }
+    }
+}
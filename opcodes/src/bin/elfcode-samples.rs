@@ -0,0 +1,28 @@
+use opcodes::generate::generate;
+
+// elfcode-samples <seed> [samples] [program length] > input
+//
+// Writes a day 16 style input to stdout, and the answers it was built from to stderr.
+fn main() {
+    let args: Vec<u64> = std::env::args()
+        .skip(1)
+        .map(|arg| arg.parse().expect("arguments must be integers"))
+        .collect();
+    let (seed, samples, program_len) = match args[..] {
+        [seed] => (seed, 800, 900),
+        [seed, samples] => (seed, samples, 900),
+        [seed, samples, program_len] => (seed, samples, program_len),
+        _ => {
+            eprintln!("usage: elfcode-samples <seed> [samples] [program length]");
+            std::process::exit(2);
+        }
+    };
+
+    let generated = generate(seed, samples as usize, program_len as usize);
+    print!("{}", generated.file);
+
+    for (op, opcode) in generated.mapping.iter().enumerate() {
+        eprintln!("{:>2} = {}", op, opcode);
+    }
+    eprintln!("registers after the program: {:?}", generated.registers);
+}
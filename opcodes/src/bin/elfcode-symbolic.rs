@@ -0,0 +1,52 @@
+use std::io::Read;
+use std::rc::Rc;
+
+use opcodes::symbolic::{Explorer, Expr};
+
+fn usage() -> ! {
+    eprintln!("usage: elfcode-symbolic [rN=value]... [--max-steps n] [--max-paths n] < program");
+    std::process::exit(2);
+}
+
+// Registers not given a value on the command line stay symbolic.
+fn main() {
+    let mut explorer: Explorer = Explorer::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut number = || {
+            args.next()
+                .and_then(|n| n.parse().ok())
+                .unwrap_or_else(|| usage())
+        };
+        match arg.as_str() {
+            "--max-steps" => explorer.max_steps = number(),
+            "--max-paths" => explorer.max_paths = number() as usize,
+            _ => {
+                let (register, value) = arg
+                    .strip_prefix('r')
+                    .and_then(|arg| arg.split_once('='))
+                    .and_then(|(register, value)| {
+                        Some((register.parse().ok()?, value.parse().ok()?))
+                    })
+                    .filter(|&(register, _): &(usize, _)| register < explorer.initial.len())
+                    .unwrap_or_else(|| usage());
+                explorer.initial[register] = Rc::new(Expr::Const(value));
+            }
+        }
+    }
+
+    let mut input = String::new();
+    std::io::stdin()
+        .lock()
+        .read_to_string(&mut input)
+        .expect("could not read stdin");
+
+    let program = opcodes::parse_program(&input).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    });
+
+    for path in explorer.explore(&program) {
+        println!("{}", path);
+    }
+}
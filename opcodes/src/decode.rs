@@ -0,0 +1,302 @@
+use crate::Opcode::*;
+use crate::{Arithmetic, Error, Fault, Instruction, Operand, Program, Reg, Register};
+
+// One instruction with its immediates already converted to registers and its register operands
+// already checked against N, so executing it can't fail except by overflowing.
+#[derive(Clone, Copy, Debug)]
+enum Op<R> {
+    AddR(usize, usize, usize),
+    AddI(usize, R, usize),
+    MulR(usize, usize, usize),
+    MulI(usize, R, usize),
+    AndR(usize, usize, usize),
+    AndI(usize, R, usize),
+    OrR(usize, usize, usize),
+    OrI(usize, R, usize),
+    SetR(usize, usize),
+    SetI(R, usize),
+    GtIR(R, usize, usize),
+    GtRI(usize, R, usize),
+    GtRR(usize, usize, usize),
+    EqIR(R, usize, usize),
+    EqRI(usize, R, usize),
+    EqRR(usize, usize, usize),
+}
+
+// A program decoded once up front for fast execution.  Unlike Machine, which goes through
+// try_eval_one for every step, this runs straight off a dense array of pre-validated operations
+// and updates the registers in place.
+#[derive(Clone, Debug)]
+pub struct Decoded<R = Reg, const N: usize = 6> {
+    ops: Vec<Op<R>>,
+    // Each instruction's index as a register value, if the instruction pointer is bound.
+    ips: Vec<R>,
+    ip_binding: Option<usize>,
+    arithmetic: Arithmetic,
+}
+
+impl<R: Register, const N: usize> Decoded<R, N> {
+    // Validates every operand, reporting the first bad one as a fault at its instruction.
+    pub fn new(program: &Program, arithmetic: Arithmetic) -> Result<Self, Fault> {
+        if let Some(binding) = program.ip_binding {
+            if binding >= N {
+                return Err(Fault {
+                    ip: 0,
+                    error: Error::BindingOutOfRange {
+                        index: binding,
+                        width: N,
+                    },
+                });
+            }
+        }
+
+        let ops = program
+            .instructions
+            .iter()
+            .enumerate()
+            .map(|(ip, &instruction)| {
+                decode::<R, N>(instruction).map_err(|error| Fault { ip, error })
+            })
+            .collect::<Result<_, _>>()?;
+        let ips = match program.ip_binding {
+            Some(_) => (0..program.instructions.len())
+                .map(|ip| {
+                    R::try_from(ip)
+                        .ok()
+                        .expect("instruction pointer does not fit in a register")
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        Ok(Decoded {
+            ops,
+            ips,
+            ip_binding: program.ip_binding,
+            arithmetic,
+        })
+    }
+
+    // Runs from instruction 0 until the program halts, returning the number of instructions
+    // executed.  As with Machine, a faulting instruction leaves the registers untouched.
+    pub fn run(&self, registers: &mut [R; N]) -> Result<u64, Fault> {
+        let mut ip = 0;
+        let mut steps = 0;
+
+        while let Some(&op) = self.ops.get(ip) {
+            let saved = self.ip_binding.map(|binding| {
+                let saved = registers[binding];
+                registers[binding] = self.ips[ip];
+                saved
+            });
+
+            if let Err(error) = self.execute(op, registers) {
+                if let (Some(binding), Some(saved)) = (self.ip_binding, saved) {
+                    registers[binding] = saved;
+                }
+                return Err(Fault { ip, error });
+            }
+            steps += 1;
+
+            ip = match self.ip_binding {
+                Some(binding) => registers[binding]
+                    .try_into()
+                    .ok()
+                    .and_then(|ip: usize| ip.checked_add(1))
+                    .unwrap_or(usize::MAX),
+                None => ip + 1,
+            };
+        }
+
+        Ok(steps)
+    }
+
+    #[inline(always)]
+    fn execute(&self, op: Op<R>, r: &mut [R; N]) -> Result<(), Error> {
+        // Every index was checked against N when the op was decoded.
+        let get = |r: &[R; N], i: usize| unsafe { *r.get_unchecked(i) };
+        let set = |r: &mut [R; N], i: usize, value: R| unsafe { *r.get_unchecked_mut(i) = value };
+        let flag = |condition| if condition { R::ONE } else { R::ZERO };
+        let overflow = |opcode| move || Error::Overflow { opcode };
+
+        match op {
+            Op::AddR(a, b, c) => set(
+                r,
+                c,
+                self.arithmetic
+                    .add(get(r, a), get(r, b))
+                    .ok_or_else(overflow(Addr))?,
+            ),
+            Op::AddI(a, b, c) => set(
+                r,
+                c,
+                self.arithmetic
+                    .add(get(r, a), b)
+                    .ok_or_else(overflow(Addi))?,
+            ),
+            Op::MulR(a, b, c) => set(
+                r,
+                c,
+                self.arithmetic
+                    .mul(get(r, a), get(r, b))
+                    .ok_or_else(overflow(Mulr))?,
+            ),
+            Op::MulI(a, b, c) => set(
+                r,
+                c,
+                self.arithmetic
+                    .mul(get(r, a), b)
+                    .ok_or_else(overflow(Muli))?,
+            ),
+            Op::AndR(a, b, c) => set(r, c, get(r, a) & get(r, b)),
+            Op::AndI(a, b, c) => set(r, c, get(r, a) & b),
+            Op::OrR(a, b, c) => set(r, c, get(r, a) | get(r, b)),
+            Op::OrI(a, b, c) => set(r, c, get(r, a) | b),
+            Op::SetR(a, c) => set(r, c, get(r, a)),
+            Op::SetI(a, c) => set(r, c, a),
+            Op::GtIR(a, b, c) => set(r, c, flag(a > get(r, b))),
+            Op::GtRI(a, b, c) => set(r, c, flag(get(r, a) > b)),
+            Op::GtRR(a, b, c) => set(r, c, flag(get(r, a) > get(r, b))),
+            Op::EqIR(a, b, c) => set(r, c, flag(a == get(r, b))),
+            Op::EqRI(a, b, c) => set(r, c, flag(get(r, a) == b)),
+            Op::EqRR(a, b, c) => set(r, c, flag(get(r, a) == get(r, b))),
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+fn decode<R: Register, const N: usize>(instruction: Instruction) -> Result<Op<R>, Error> {
+    let Instruction(opcode, a, b, c) = instruction;
+    let register = |operand, index: usize| {
+        if index < N {
+            Ok(index)
+        } else {
+            Err(Error::RegisterOutOfRange {
+                opcode,
+                operand,
+                index,
+                width: N,
+            })
+        }
+    };
+    let immediate = |operand, value| {
+        R::try_from(value).map_err(|_| Error::ImmediateOutOfRange {
+            opcode,
+            operand,
+            value,
+        })
+    };
+
+    // Operands are checked in the same order as try_eval_one, so the same error is reported.
+    let c = || register(Operand::C, c);
+    let rr = || Ok::<_, Error>((register(Operand::A, a)?, register(Operand::B, b)?, c()?));
+    let ri = || Ok::<_, Error>((register(Operand::A, a)?, immediate(Operand::B, b)?, c()?));
+    let ir = || Ok::<_, Error>((immediate(Operand::A, a)?, register(Operand::B, b)?, c()?));
+
+    Ok(match opcode {
+        Addr => rr().map(|(a, b, c)| Op::AddR(a, b, c))?,
+        Addi => ri().map(|(a, b, c)| Op::AddI(a, b, c))?,
+        Mulr => rr().map(|(a, b, c)| Op::MulR(a, b, c))?,
+        Muli => ri().map(|(a, b, c)| Op::MulI(a, b, c))?,
+        Banr => rr().map(|(a, b, c)| Op::AndR(a, b, c))?,
+        Bani => ri().map(|(a, b, c)| Op::AndI(a, b, c))?,
+        Borr => rr().map(|(a, b, c)| Op::OrR(a, b, c))?,
+        Bori => ri().map(|(a, b, c)| Op::OrI(a, b, c))?,
+        Setr => Op::SetR(register(Operand::A, a)?, c()?),
+        Seti => Op::SetI(immediate(Operand::A, a)?, c()?),
+        Gtir => ir().map(|(a, b, c)| Op::GtIR(a, b, c))?,
+        Gtri => ri().map(|(a, b, c)| Op::GtRI(a, b, c))?,
+        Gtrr => rr().map(|(a, b, c)| Op::GtRR(a, b, c))?,
+        Eqir => ir().map(|(a, b, c)| Op::EqIR(a, b, c))?,
+        Eqri => ri().map(|(a, b, c)| Op::EqRI(a, b, c))?,
+        Eqrr => rr().map(|(a, b, c)| Op::EqRR(a, b, c))?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Machine;
+
+    const DIVISORS: &str = "#ip 4
+seti 1 0 1
+seti 1 0 2
+mulr 1 2 3
+eqrr 3 0 3
+addr 3 4 4
+addi 4 1 4
+addr 1 5 5
+addi 2 1 2
+gtrr 2 0 3
+addr 4 3 4
+seti 1 0 4
+addi 1 1 1
+gtrr 1 0 3
+addr 3 4 4
+seti 0 0 4
+";
+
+    #[test]
+    fn agrees_with_machine() {
+        let program = crate::parse_program(DIVISORS).unwrap();
+        let decoded: Decoded = Decoded::new(&program, Arithmetic::Checked).unwrap();
+
+        for n in 0..30 {
+            let mut machine: Machine = Machine::from(program.clone());
+            machine.registers[0] = n;
+            machine.run().unwrap();
+
+            let mut registers = [n, 0, 0, 0, 0, 0];
+            assert_eq!(decoded.run(&mut registers), Ok(machine.steps()));
+            assert_eq!(registers, machine.registers);
+        }
+    }
+
+    #[test]
+    fn faults() {
+        let program = crate::parse_program("seti 1 0 6\n").unwrap();
+        assert_eq!(
+            Decoded::<u64, 6>::new(&program, Arithmetic::Checked).unwrap_err(),
+            Fault {
+                ip: 0,
+                error: Error::RegisterOutOfRange {
+                    opcode: Seti,
+                    operand: Operand::C,
+                    index: 6,
+                    width: 6,
+                },
+            }
+        );
+
+        let program = crate::parse_program("#ip 2\nseti 255 0 0\naddi 0 1 1\n").unwrap();
+        let mut registers = [0u8, 7, 9];
+        let decoded = Decoded::new(&program, Arithmetic::Checked).unwrap();
+        assert_eq!(
+            decoded.run(&mut registers),
+            Err(Fault {
+                ip: 1,
+                error: Error::Overflow { opcode: Addi },
+            })
+        );
+        assert_eq!(registers, [255, 7, 0]);
+
+        let decoded = Decoded::new(&program, Arithmetic::Wrapping).unwrap();
+        assert_eq!(decoded.run(&mut registers), Ok(2));
+        assert_eq!(registers, [255, 0, 1]);
+        assert_eq!(
+            Decoded::<u8, 2>::new(&program, Arithmetic::Checked).map(|_| ()),
+            Err(Fault {
+                ip: 0,
+                error: Error::BindingOutOfRange { index: 2, width: 2 },
+            })
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use crate::Opcode::*;
+use crate::{Arithmetic, Instruction, Op, Opcode, Program, Reg, Source};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Value {
+    Register(usize),
+    Constant(Reg),
+}
+
+fn operator(opcode: Opcode) -> &'static str {
+    match opcode {
+        Addr | Addi => "+",
+        Mulr | Muli => "*",
+        Banr | Bani => "&",
+        Borr | Bori => "|",
+        Gtir | Gtri | Gtrr => ">",
+        Eqir | Eqri | Eqrr => "==",
+        Setr | Seti => "",
+    }
+}
+
+// Renders elfcode as pseudo-code.  Reads of the instruction pointer register are replaced by the
+// (known) index of the instruction doing the reading, and writes to it are shown as jumps.
+pub struct Disassembler<'a> {
+    program: &'a Program,
+    names: Vec<Option<String>>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Disassembler {
+            program,
+            names: Vec::new(),
+        }
+    }
+
+    pub fn rename<S: Into<String>>(&mut self, register: usize, name: S) {
+        if self.names.len() <= register {
+            self.names.resize(register + 1, None);
+        }
+        self.names[register] = Some(name.into());
+    }
+
+    fn name(&self, register: usize) -> String {
+        match self.names.get(register) {
+            Some(Some(name)) => name.clone(),
+            _ => format!("r{}", register),
+        }
+    }
+
+    fn value(&self, index: usize, source: Source, operand: Op) -> Option<Value> {
+        match source {
+            Source::Register if Some(operand) == self.program.ip_binding => {
+                Some(Value::Constant(index as Reg))
+            }
+            Source::Register => Some(Value::Register(operand)),
+            Source::Immediate => Some(Value::Constant(operand as Reg)),
+            Source::Ignored => None,
+        }
+    }
+
+    fn show(&self, value: Value) -> String {
+        match value {
+            Value::Register(register) => self.name(register),
+            Value::Constant(constant) => constant.to_string(),
+        }
+    }
+
+    fn goto(&self, target: Reg) -> String {
+        if target < self.program.instructions.len() as Reg {
+            format!("goto {}", target)
+        } else {
+            format!("goto {} (halts)", target)
+        }
+    }
+
+    pub fn line(&self, index: usize) -> String {
+        let Instruction(opcode, a, b, c) = self.program.instructions[index];
+        let (source_a, source_b) = opcode.sources();
+        let a = self
+            .value(index, source_a, a)
+            .expect("every opcode reads A");
+        let b = self.value(index, source_b, b);
+        let jump = Some(c) == self.program.ip_binding;
+
+        let folded = match (a, b) {
+            (Value::Constant(a), None) => opcode.apply(Arithmetic::Checked, a, 0),
+            (Value::Constant(a), Some(Value::Constant(b))) => {
+                opcode.apply(Arithmetic::Checked, a, b)
+            }
+            _ => None,
+        };
+        if let Some(value) = folded {
+            return if jump {
+                match value.checked_add(1) {
+                    Some(target) => self.goto(target),
+                    None => "halt".to_string(),
+                }
+            } else {
+                format!("{} = {}", self.name(c), value)
+            };
+        }
+
+        let expression = match b {
+            Some(b) => format!("{} {} {}", self.show(a), operator(opcode), self.show(b)),
+            None => self.show(a),
+        };
+
+        if opcode.is_comparison() {
+            return if jump {
+                format!("if {} then goto 2 else goto 1", expression)
+            } else {
+                format!(
+                    "if {} then {} = 1 else {} = 0",
+                    expression,
+                    self.name(c),
+                    self.name(c)
+                )
+            };
+        }
+
+        if !jump {
+            return format!("{} = {}", self.name(c), expression);
+        }
+
+        // The target depends on some other register, so all we can do is show the arithmetic.
+        // Adding an offset is by far the most common case, so fold the +1 into it.
+        let target = match (opcode, a, b) {
+            (Addr, Value::Constant(k), Some(Value::Register(register)))
+            | (Addr, Value::Register(register), Some(Value::Constant(k)))
+            | (Addi, Value::Register(register), Some(Value::Constant(k))) => {
+                match k.checked_add(1) {
+                    Some(k) => format!("{} + {}", self.name(register), k),
+                    None => format!("({}) + 1", expression),
+                }
+            }
+            (Setr, Value::Register(register), _) => format!("{} + 1", self.name(register)),
+            _ => format!("({}) + 1", expression),
+        };
+        format!("goto {} (computed)", target)
+    }
+
+    pub fn listing(&self) -> String {
+        (0..self.program.instructions.len())
+            .map(|index| format!("{:>3}: {}\n", index, self.line(index)))
+            .collect()
+    }
+}
+
+impl<'a> std::fmt::Display for Disassembler<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.listing())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_program;
+
+    #[test]
+    fn example() {
+        let program = parse_program(
+            "#ip 0
+seti 5 0 1
+seti 6 0 2
+addi 0 1 0
+addr 1 2 3
+setr 1 0 0
+seti 8 0 4
+seti 9 0 5
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Disassembler::new(&program).listing(),
+            "  0: r1 = 5
+  1: r2 = 6
+  2: goto 4
+  3: r3 = r1 + r2
+  4: goto r1 + 1 (computed)
+  5: r4 = 8
+  6: r5 = 9
+"
+        );
+    }
+
+    #[test]
+    fn largest_offset() {
+        let program = parse_program("#ip 0\naddi 1 18446744073709551615 0\n").unwrap();
+        assert_eq!(
+            Disassembler::new(&program).line(0),
+            "goto (r1 + 18446744073709551615) + 1 (computed)"
+        );
+    }
+
+    #[test]
+    fn jumps_and_names() {
+        let program = parse_program(
+            "#ip 3
+eqrr 1 5 1
+addr 1 3 3
+addi 3 1 3
+gtrr 4 5 3
+mulr 3 3 3
+setr 3 4 1
+mulr 2 4 1
+",
+        )
+        .unwrap();
+
+        let mut disassembler = Disassembler::new(&program);
+        disassembler.rename(5, "target");
+        disassembler.rename(2, "i");
+
+        assert_eq!(
+            disassembler.line(0),
+            "if r1 == target then r1 = 1 else r1 = 0"
+        );
+        assert_eq!(disassembler.line(1), "goto r1 + 2 (computed)");
+        assert_eq!(disassembler.line(2), "goto 4");
+        assert_eq!(
+            disassembler.line(3),
+            "if r4 > target then goto 2 else goto 1"
+        );
+        assert_eq!(disassembler.line(4), "goto 17 (halts)");
+        assert_eq!(disassembler.line(5), "r1 = 5");
+        assert_eq!(disassembler.line(6), "r1 = i * r4");
+    }
+}
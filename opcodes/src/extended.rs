@@ -0,0 +1,208 @@
+use std::convert::TryInto;
+
+use crate::{Arithmetic, InstructionSet, Opcode, Register, Source};
+
+// The standard sixteen opcodes plus a few that the puzzles never had:
+//
+//   divr/divi  a / b, rounding down
+//   modr/modi  the remainder of a / b
+//   shli/shri  a shifted left/right by b bits
+//
+// Dividing by zero, and shifting by the register's width or more, are undefined and fault.  A left
+// shift is a multiplication, so it overflows according to the machine's Arithmetic like muli does.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Extended {
+    Standard(Opcode),
+    Divr,
+    Divi,
+    Modr,
+    Modi,
+    Shli,
+    Shri,
+}
+
+use Extended::*;
+use Opcode::*;
+
+impl From<Opcode> for Extended {
+    fn from(opcode: Opcode) -> Self {
+        Standard(opcode)
+    }
+}
+
+fn bits<R: Register>(b: R) -> Option<u32> {
+    b.try_into()
+        .ok()
+        .and_then(|bits: usize| bits.try_into().ok())
+}
+
+impl InstructionSet for Extended {
+    const ALL: &'static [Self] = &[
+        Standard(Addr),
+        Standard(Addi),
+        Standard(Mulr),
+        Standard(Muli),
+        Standard(Banr),
+        Standard(Bani),
+        Standard(Borr),
+        Standard(Bori),
+        Standard(Setr),
+        Standard(Seti),
+        Standard(Gtir),
+        Standard(Gtri),
+        Standard(Gtrr),
+        Standard(Eqir),
+        Standard(Eqri),
+        Standard(Eqrr),
+        Divr,
+        Divi,
+        Modr,
+        Modi,
+        Shli,
+        Shri,
+    ];
+
+    fn mnemonic(self) -> &'static str {
+        match self {
+            Standard(opcode) => opcode.mnemonic(),
+            Divr => "divr",
+            Divi => "divi",
+            Modr => "modr",
+            Modi => "modi",
+            Shli => "shli",
+            Shri => "shri",
+        }
+    }
+
+    fn sources(self) -> (Source, Source) {
+        match self {
+            Standard(opcode) => opcode.sources(),
+            Divr | Modr => (Source::Register, Source::Register),
+            Divi | Modi | Shli | Shri => (Source::Register, Source::Immediate),
+        }
+    }
+
+    fn apply<R: Register>(self, arithmetic: Arithmetic, a: R, b: R) -> Option<R> {
+        match self {
+            Standard(opcode) => opcode.apply(arithmetic, a, b),
+            Divr | Divi => a.checked_div(b),
+            Modr | Modi => a.checked_rem(b),
+            Shli => arithmetic.mul(a, R::ONE.checked_shl(bits(b)?)?),
+            Shri => a.checked_shr(bits(b)?),
+        }
+    }
+
+    fn defined<R: Register>(self, _a: R, b: R) -> bool {
+        match self {
+            Standard(_) => true,
+            Divr | Divi | Modr | Modi => b != R::ZERO,
+            Shli | Shri => bits(b).and_then(|bits| R::ONE.checked_shl(bits)).is_some(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::generate::XorShift;
+    use crate::identify::{identify_in, Sample};
+    use crate::{eval_one, parse_program_in, try_eval_one, Error, Fault, Machine, Reg};
+
+    // Sums the decimal digits of 1234, then multiplies the sum by four.
+    const DIGITS: &str = "#ip 5
+seti 1234 0 0
+modi 0 10 2
+addr 1 2 1
+divi 0 10 0
+eqri 0 0 2
+addr 2 5 5
+seti 0 0 5
+shli 1 2 1
+";
+
+    #[test]
+    fn parse_and_run() {
+        let program = parse_program_in(Extended::ALL, DIGITS).unwrap();
+        assert_eq!(program.instructions[1], crate::Instruction(Modi, 0, 10, 2));
+        assert_eq!(
+            program.instructions[2],
+            crate::Instruction(Addr.into(), 1, 2, 1)
+        );
+        assert_eq!(program.to_string(), DIGITS);
+        assert!(crate::parse_program(DIGITS).is_err());
+
+        let mut machine: Machine<Reg, 6, Extended> = Machine::from(program);
+        machine.run().unwrap();
+        assert_eq!(machine.registers[..2], [0, 40]);
+    }
+
+    #[test]
+    fn faults() {
+        let program = parse_program_in(Extended::ALL, "seti 7 0 0\ndivr 0 1 2\n").unwrap();
+        let mut machine: Machine<Reg, 4, Extended> = Machine::from(program);
+        assert_eq!(
+            machine.run(),
+            Err(Fault {
+                ip: 1,
+                error: Error::Undefined { opcode: Divr },
+            })
+        );
+
+        assert_eq!(try_eval_one(Shli, [3u8], [0, 0, 6, 0]), Ok([192]));
+        assert_eq!(
+            try_eval_one(Shli, [3u8], [0, 0, 7, 0]),
+            Err(Error::Overflow { opcode: Shli })
+        );
+        assert_eq!(
+            try_eval_one(Shli, [3u8], [0, 0, 8, 0]),
+            Err(Error::Undefined { opcode: Shli })
+        );
+        assert_eq!(
+            try_eval_one(Shri, [3u8], [0, 0, 8, 0]),
+            Err(Error::Undefined { opcode: Shri })
+        );
+        assert_eq!(
+            Error::Undefined { opcode: Divr }.to_string(),
+            "divr is undefined for its inputs"
+        );
+        assert_eq!(
+            Error::Overflow {
+                opcode: Standard(Addr)
+            }
+            .to_string(),
+            "addr overflowed"
+        );
+        assert_eq!(try_eval_one(Modi, [17u8], [0, 0, 5, 0]), Ok([2]));
+    }
+
+    #[test]
+    fn identification() {
+        let mut rng = XorShift::new(16);
+        let mut mapping = Extended::ALL.to_vec();
+        rng.shuffle(&mut mapping);
+
+        let mut samples = Vec::new();
+        while samples.len() < 1000 {
+            let op = rng.below(mapping.len() as u64) as usize;
+            let instruction = [
+                op,
+                rng.below(4) as usize,
+                rng.below(4) as usize,
+                rng.below(4) as usize,
+            ];
+            let before = [rng.below(16), rng.below(16), rng.below(16), rng.below(16)];
+            // Division by zero can't make a sample.
+            if try_eval_one(mapping[op], before, instruction).is_ok() {
+                samples.push(Sample {
+                    before,
+                    instruction,
+                    after: eval_one(mapping[op], before, instruction),
+                });
+            }
+        }
+
+        let found = identify_in(Extended::ALL, &samples).unwrap();
+        assert_eq!(found.len(), mapping.len());
+        assert!(found.iter().all(|(&op, &opcode)| mapping[op] == opcode));
+    }
+}
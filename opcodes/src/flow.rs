@@ -0,0 +1,285 @@
+use std::collections::BTreeSet;
+
+use crate::disasm::Disassembler;
+use crate::Opcode::*;
+use crate::{Arithmetic, Instruction, Program, Reg, Source};
+
+// How control leaves an instruction, or a basic block.  Targets may be past the end of the
+// program, which halts it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Exit {
+    Next(usize),
+    Jump(usize),
+    // A comparison result (0 or 1) was added to the instruction pointer.
+    Branch { taken: usize, not_taken: usize },
+    Dynamic,
+}
+
+impl Exit {
+    pub fn targets(self) -> Vec<usize> {
+        match self {
+            Exit::Next(target) | Exit::Jump(target) => vec![target],
+            Exit::Branch { taken, not_taken } => vec![not_taken, taken],
+            Exit::Dynamic => vec![],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Block {
+    pub start: usize,
+    // exclusive
+    pub end: usize,
+    pub exit: Exit,
+}
+
+fn writes_comparison(instruction: Option<&Instruction>, register: usize) -> bool {
+    matches!(instruction, Some(&Instruction(opcode, _, _, c)) if c == register && opcode.is_comparison())
+}
+
+// Works out where an instruction can go next, without knowing anything about the registers other
+// than the instruction pointer itself.
+fn exit(program: &Program, index: usize) -> Exit {
+    let Instruction(opcode, a, b, c) = program.instructions[index];
+    let ip = match program.ip_binding {
+        Some(ip) if ip == c => ip,
+        _ => return Exit::Next(index + 1),
+    };
+
+    let value = |source, operand| match source {
+        Source::Register if operand == ip => Some(index as Reg),
+        Source::Register => None,
+        Source::Immediate => Some(operand as Reg),
+        Source::Ignored => Some(0),
+    };
+    let (source_a, source_b) = opcode.sources();
+
+    match (value(source_a, a), value(source_b, b)) {
+        (Some(a), Some(b)) => match opcode.apply(Arithmetic::Checked, a, b) {
+            Some(target) if target < Reg::MAX => Exit::Jump(target as usize + 1),
+            _ => Exit::Jump(usize::MAX),
+        },
+        _ if opcode.is_comparison() => Exit::Branch {
+            taken: 2,
+            not_taken: 1,
+        },
+        // "addr rX ip ip" right after setting rX with a comparison is a conditional skip.
+        (Some(_), None) | (None, Some(_))
+            if opcode == Addr
+                && writes_comparison(
+                    index
+                        .checked_sub(1)
+                        .and_then(|i| program.instructions.get(i)),
+                    if a == ip { b } else { a },
+                ) =>
+        {
+            Exit::Branch {
+                taken: index + 2,
+                not_taken: index + 1,
+            }
+        }
+        _ => Exit::Dynamic,
+    }
+}
+
+pub struct Graph<'a> {
+    program: &'a Program,
+    blocks: Vec<Block>,
+}
+
+impl<'a> Graph<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        let len = program.instructions.len();
+        let mut exits: Vec<Exit> = (0..len).map(|index| exit(program, index)).collect();
+
+        // A conditional skip is only trustworthy if nothing else can jump between the comparison
+        // and the add.
+        let jumped_to = |exits: &[Exit]| -> BTreeSet<usize> {
+            exits
+                .iter()
+                .filter(|exit| !matches!(exit, Exit::Next(_)))
+                .flat_map(|exit| exit.targets())
+                .collect()
+        };
+        let targets = jumped_to(&exits);
+        for (index, exit) in exits.iter_mut().enumerate() {
+            if let Exit::Branch { taken, .. } = *exit {
+                if taken == index + 2 && targets.contains(&index) {
+                    *exit = Exit::Dynamic;
+                }
+            }
+        }
+
+        let mut leaders = jumped_to(&exits);
+        leaders.insert(0);
+        leaders.extend(
+            exits
+                .iter()
+                .enumerate()
+                .filter(|(_, exit)| !matches!(exit, Exit::Next(_)))
+                .map(|(index, _)| index + 1),
+        );
+        let leaders: Vec<usize> = leaders.into_iter().filter(|&l| l < len).collect();
+
+        let blocks = leaders
+            .iter()
+            .enumerate()
+            .map(|(i, &start)| {
+                let end = leaders.get(i + 1).cloned().unwrap_or(len);
+                Block {
+                    start,
+                    end,
+                    exit: exits[end - 1],
+                }
+            })
+            .collect();
+
+        Graph { program, blocks }
+    }
+
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    pub fn dot(&self, disassembler: &Disassembler) -> String {
+        let len = self.program.instructions.len();
+        let node = |target: usize| {
+            if target < len {
+                format!("i{}", target)
+            } else {
+                "halt".to_string()
+            }
+        };
+
+        let mut dot = String::from("digraph elfcode {\n");
+        dot.push_str("    node [shape=box, fontname=monospace];\n");
+        dot.push_str("    halt [shape=doublecircle];\n");
+
+        let mut dynamic = false;
+        for block in &self.blocks {
+            let label: String = (block.start..block.end)
+                .map(|index| format!("{}: {}\\l", index, disassembler.line(index)))
+                .collect();
+            dot.push_str(&format!(
+                "    i{} [label=\"{}\"];\n",
+                block.start,
+                label.replace('"', "\\\"")
+            ));
+
+            match block.exit {
+                Exit::Next(target) | Exit::Jump(target) => {
+                    dot.push_str(&format!("    i{} -> {};\n", block.start, node(target)))
+                }
+                Exit::Branch { taken, not_taken } => {
+                    dot.push_str(&format!(
+                        "    i{} -> {} [label=\"true\"];\n",
+                        block.start,
+                        node(taken)
+                    ));
+                    dot.push_str(&format!(
+                        "    i{} -> {} [label=\"false\"];\n",
+                        block.start,
+                        node(not_taken)
+                    ));
+                }
+                Exit::Dynamic => {
+                    dynamic = true;
+                    dot.push_str(&format!(
+                        "    i{} -> computed [style=dashed];\n",
+                        block.start
+                    ));
+                }
+            }
+        }
+
+        if dynamic {
+            dot.push_str("    computed [shape=diamond, label=\"?\"];\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_program;
+
+    #[test]
+    fn loop_with_skip() {
+        let program = parse_program(
+            "#ip 3
+seti 1 0 2
+eqrr 2 5 1
+addr 1 3 3
+addi 2 1 2
+seti 0 0 3
+addr 0 3 3
+",
+        )
+        .unwrap();
+
+        let graph = Graph::new(&program);
+        assert_eq!(
+            graph.blocks(),
+            &[
+                Block {
+                    start: 0,
+                    end: 1,
+                    exit: Exit::Next(1)
+                },
+                Block {
+                    start: 1,
+                    end: 3,
+                    exit: Exit::Branch {
+                        taken: 4,
+                        not_taken: 3
+                    }
+                },
+                Block {
+                    start: 3,
+                    end: 4,
+                    exit: Exit::Next(4)
+                },
+                Block {
+                    start: 4,
+                    end: 5,
+                    exit: Exit::Jump(1)
+                },
+                Block {
+                    start: 5,
+                    end: 6,
+                    exit: Exit::Dynamic
+                },
+            ]
+        );
+
+        let dot = graph.dot(&Disassembler::new(&program));
+        assert!(dot.contains("    i1 -> i4 [label=\"true\"];\n"));
+        assert!(dot.contains("    i4 -> i1;\n"));
+        assert!(dot.contains("    i5 -> computed [style=dashed];\n"));
+        assert!(dot.contains("    i0 [label=\"0: r2 = 1\\l\"];\n"));
+    }
+
+    #[test]
+    fn skip_into_a_jump_target_is_dynamic() {
+        let program = parse_program(
+            "#ip 0
+eqrr 2 5 1
+addr 1 0 0
+seti 0 0 0
+seti 0 0 0
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Graph::new(&program).blocks()[1],
+            Block {
+                start: 1,
+                end: 2,
+                exit: Exit::Dynamic
+            }
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use crate::identify::Sample;
+use crate::{
+    eval_one, try_eval_one, Instruction, Machine, Op, Opcode, Reg, SampleFile, ALL_OPCODES,
+};
+
+// A small xorshift generator: enough for test data, and the same sequence on every platform.
+#[derive(Clone, Debug)]
+pub struct XorShift(u64);
+
+impl XorShift {
+    // Xorshift gets stuck at zero, so that seed is nudged.
+    pub fn new(seed: u64) -> Self {
+        XorShift(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i as u64 + 1) as usize);
+        }
+    }
+}
+
+// A day 16 input along with the answers it was built from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Generated {
+    // The opcode that each number stands for.
+    pub mapping: Vec<Opcode>,
+    pub file: SampleFile,
+    // What the program leaves in the registers, run from all zeroes.
+    pub registers: [Reg; 4],
+}
+
+// Picks an operand that's valid whichever way the opcode reads it.  Registers are small, as in
+// the real puzzle input.
+fn operands(rng: &mut XorShift) -> [Op; 3] {
+    [rng.below(4) as Op, rng.below(4) as Op, rng.below(4) as Op]
+}
+
+// Builds a day 16 style input from a seed: a random assignment of numbers to opcodes, `samples`
+// samples of it, and a straight-line program of `program_len` instructions that never overflows.
+pub fn generate(seed: u64, samples: usize, program_len: usize) -> Generated {
+    let mut rng = XorShift::new(seed);
+    let mut mapping = ALL_OPCODES.to_vec();
+    rng.shuffle(&mut mapping);
+
+    let samples = (0..samples)
+        .map(|_| {
+            let op = rng.below(mapping.len() as u64) as Op;
+            let [a, b, c] = operands(&mut rng);
+            let before = [rng.below(4), rng.below(4), rng.below(4), rng.below(4)];
+            let instruction = [op, a, b, c];
+            Sample {
+                before,
+                instruction,
+                after: eval_one(mapping[op], before, instruction),
+            }
+        })
+        .collect();
+
+    let mut registers = [0; 4];
+    let mut program = Vec::with_capacity(program_len);
+    while program.len() < program_len {
+        let op = rng.below(mapping.len() as u64) as Op;
+        let [a, b, c] = operands(&mut rng);
+        // Anything that would overflow is just drawn again.
+        if let Ok(after) = try_eval_one(mapping[op], registers, [op, a, b, c]) {
+            registers = after;
+            program.push([op, a, b, c]);
+        }
+    }
+
+    Generated {
+        mapping,
+        file: SampleFile { samples, program },
+        registers,
+    }
+}
+
+impl Generated {
+    // The program with the true opcodes filled in.
+    pub fn machine(&self) -> Machine<Reg, 4> {
+        let program = self
+            .file
+            .program
+            .iter()
+            .map(|&[op, a, b, c]| Instruction(self.mapping[op], a, b, c))
+            .collect();
+        Machine::new(program, None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::identify::{identify, IdentifyError};
+
+    #[test]
+    fn round_trip() {
+        for seed in 0..20 {
+            let generated = generate(seed, 200, 30);
+            assert_eq!(
+                crate::parse_samples(&generated.file.to_string()),
+                Ok(generated.file.clone())
+            );
+
+            let mut machine = generated.machine();
+            machine.run().unwrap();
+            assert_eq!(machine.registers, generated.registers);
+        }
+    }
+
+    // Whatever the solver says must agree with the mapping the samples came from.
+    #[test]
+    fn solver_finds_the_truth() {
+        let mut unique = 0;
+        for seed in 0..200 {
+            let samples = 1 + seed as usize % 60;
+            let generated = generate(seed, samples, 0);
+            let agrees = |mapping: &std::collections::HashMap<Op, Opcode>| {
+                mapping
+                    .iter()
+                    .all(|(&op, &opcode)| generated.mapping[op] == opcode)
+            };
+
+            match identify(&generated.file.samples) {
+                Ok(mapping) => {
+                    assert!(agrees(&mapping), "seed {}: {:?}", seed, mapping);
+                    unique += 1;
+                }
+                Err(IdentifyError::Ambiguous {
+                    mappings,
+                    truncated,
+                }) => assert!(
+                    truncated || mappings.iter().any(agrees),
+                    "seed {}: the true mapping is missing",
+                    seed
+                ),
+                Err(error) => panic!("seed {}: {}", seed, error),
+            }
+        }
+        assert!(unique > 0);
+
+        // With as many samples as the real input, there's no doubt left.
+        let generated = generate(2018, 800, 0);
+        let mapping = identify(&generated.file.samples).unwrap();
+        assert_eq!(mapping.len(), 16);
+        assert!(mapping
+            .iter()
+            .all(|(&op, &opcode)| generated.mapping[op] == opcode));
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{try_eval_one, InstructionSet, Op, Opcode, Reg, Register, ALL_OPCODES};
+
+// How many mappings an ambiguous result lists before giving up on the rest.
+const MAX_MAPPINGS: usize = 100;
+
+// One observation of an unknown numeric opcode: the registers before and after it ran.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Sample<R = Reg, const N: usize = 4> {
+    pub before: [R; N],
+    pub instruction: [Op; 4],
+    pub after: [R; N],
+}
+
+// Every opcode that would have turned `before` into `after`.  A sample that names a register we
+// don't have can't have come from any of them.
+pub fn candidates<R: Register, const N: usize>(sample: &Sample<R, N>) -> Vec<Opcode> {
+    candidates_in(ALL_OPCODES, sample)
+}
+
+// The same, choosing from `opcodes` instead of the standard sixteen.
+pub fn candidates_in<O: InstructionSet, R: Register, const N: usize>(
+    opcodes: &[O],
+    sample: &Sample<R, N>,
+) -> Vec<O> {
+    opcodes
+        .iter()
+        .cloned()
+        .filter(|&opcode| {
+            Ok(sample.after) == try_eval_one(opcode, sample.before, sample.instruction)
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IdentifyError<O = Opcode> {
+    // No mapping fits this sample (an index into the slice) together with the ones before it.
+    Contradiction {
+        sample: usize,
+    },
+    // More than one mapping fits every sample.  Lists them all, unless there are more than
+    // MAX_MAPPINGS, in which case `truncated` is set.
+    Ambiguous {
+        mappings: Vec<HashMap<Op, O>>,
+        truncated: bool,
+    },
+}
+
+impl<O> std::fmt::Display for IdentifyError<O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IdentifyError::Contradiction { sample } => {
+                write!(f, "sample {} contradicts the samples before it", sample)
+            }
+            IdentifyError::Ambiguous {
+                mappings,
+                truncated,
+            } => write!(
+                f,
+                "{}{} different mappings fit the samples",
+                if *truncated { "more than " } else { "" },
+                mappings.len()
+            ),
+        }
+    }
+}
+
+impl<O: InstructionSet> std::error::Error for IdentifyError<O> {}
+
+// Finds an opcode for each numeric op in turn, with each opcode used at most once (Kuhn's
+// augmenting paths).  Returns whether every op could be given one.
+fn has_matching<O: InstructionSet>(possible: &BTreeMap<Op, Vec<O>>) -> bool {
+    fn augment<O: InstructionSet>(
+        op: Op,
+        possible: &BTreeMap<Op, Vec<O>>,
+        owner: &mut HashMap<O, Op>,
+        visited: &mut Vec<O>,
+    ) -> bool {
+        for &opcode in &possible[&op] {
+            if visited.contains(&opcode) {
+                continue;
+            }
+            visited.push(opcode);
+            let free = match owner.get(&opcode) {
+                None => true,
+                Some(&other) => augment(other, possible, owner, visited),
+            };
+            if free {
+                owner.insert(opcode, op);
+                return true;
+            }
+        }
+        false
+    }
+
+    let mut owner = HashMap::new();
+    possible
+        .keys()
+        .all(|&op| augment(op, possible, &mut owner, &mut vec![]))
+}
+
+// Every injective assignment out of what's still possible, trying the most constrained op
+// first.  Stops once it has found more than `limit`.
+fn mappings<O: InstructionSet>(
+    possible: &BTreeMap<Op, Vec<O>>,
+    chosen: &mut HashMap<Op, O>,
+    found: &mut Vec<HashMap<Op, O>>,
+    limit: usize,
+) {
+    if found.len() > limit {
+        return;
+    }
+
+    let next = possible
+        .iter()
+        .filter(|(op, _)| !chosen.contains_key(op))
+        .min_by_key(|(_, opcodes)| opcodes.len());
+    let (&op, opcodes) = match next {
+        Some(next) => next,
+        None => {
+            found.push(chosen.clone());
+            return;
+        }
+    };
+
+    for &opcode in opcodes {
+        if !chosen.values().any(|&used| used == opcode) {
+            chosen.insert(op, opcode);
+            mappings(possible, chosen, found, limit);
+            chosen.remove(&op);
+        }
+    }
+}
+
+// Works out which opcode each numeric op in the samples stands for.  Narrows each op down to
+// the opcodes every one of its samples allows, then repeatedly assigns ops with a single
+// candidate left.  If that stalls, searches the remaining possibilities for every assignment
+// that still fits.
+pub fn identify<R: Register, const N: usize>(
+    samples: &[Sample<R, N>],
+) -> Result<HashMap<Op, Opcode>, IdentifyError> {
+    identify_in(ALL_OPCODES, samples)
+}
+
+// The same, for samples of a different instruction set.  The numbers in the samples may stand
+// for any of `opcodes`, each for a different one.
+pub fn identify_in<O: InstructionSet, R: Register, const N: usize>(
+    opcodes: &[O],
+    samples: &[Sample<R, N>],
+) -> Result<HashMap<Op, O>, IdentifyError<O>> {
+    let mut possible: BTreeMap<Op, Vec<O>> = BTreeMap::new();
+
+    for (index, sample) in samples.iter().enumerate() {
+        let allowed = candidates_in(opcodes, sample);
+        let remaining = possible
+            .entry(sample.instruction[0])
+            .or_insert_with(|| opcodes.to_vec());
+        let before = remaining.len();
+        remaining.retain(|opcode| allowed.contains(opcode));
+
+        if remaining.is_empty() || remaining.len() < before && !has_matching(&possible) {
+            return Err(IdentifyError::Contradiction { sample: index });
+        }
+    }
+
+    // Elimination: an op with only one candidate left takes it away from all the others.
+    let mut known: HashMap<Op, O> = HashMap::new();
+    loop {
+        let settled: Vec<(Op, O)> = possible
+            .iter()
+            .filter(|(op, opcodes)| opcodes.len() == 1 && !known.contains_key(op))
+            .map(|(&op, opcodes)| (op, opcodes[0]))
+            .collect();
+        if settled.is_empty() {
+            break;
+        }
+
+        for (op, opcode) in settled {
+            known.insert(op, opcode);
+            for (other, opcodes) in possible.iter_mut() {
+                if *other != op {
+                    opcodes.retain(|&candidate| candidate != opcode);
+                }
+            }
+        }
+    }
+
+    if known.len() == possible.len() {
+        return Ok(known);
+    }
+
+    let mut found = Vec::new();
+    mappings(&possible, &mut known, &mut found, MAX_MAPPINGS);
+    match found.len() {
+        1 => Ok(found.pop().expect("just counted it")),
+        _ => {
+            let truncated = found.len() > MAX_MAPPINGS;
+            found.truncate(MAX_MAPPINGS);
+            Err(IdentifyError::Ambiguous {
+                mappings: found,
+                truncated,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Opcode::*;
+
+    fn sample(before: [Reg; 4], instruction: [Op; 4], after: [Reg; 4]) -> Sample {
+        Sample {
+            before,
+            instruction,
+            after,
+        }
+    }
+
+    #[test]
+    fn candidate_opcodes() {
+        assert_eq!(
+            candidates(&sample([3, 2, 1, 1], [9, 2, 1, 2], [3, 2, 2, 1])),
+            vec![Addi, Mulr, Seti]
+        );
+        assert_eq!(
+            candidates(&sample([3, 2, 1, 1], [9, 2, 5, 2], [3, 2, 2, 1])),
+            vec![Seti]
+        );
+    }
+
+    #[test]
+    fn unique() {
+        // 0 is Seti and 1 is Mulr outright, which leaves only Addi for 2.
+        let samples = [
+            sample([3, 2, 1, 1], [2, 2, 1, 2], [3, 2, 2, 1]),
+            sample([3, 2, 1, 1], [0, 2, 5, 2], [3, 2, 2, 1]),
+            sample([3, 2, 1, 1], [1, 2, 1, 2], [3, 2, 2, 1]),
+            sample([3, 3, 1, 1], [1, 0, 1, 2], [3, 3, 9, 1]),
+        ];
+        let expected: HashMap<Op, Opcode> =
+            vec![(0, Seti), (1, Mulr), (2, Addi)].into_iter().collect();
+        assert_eq!(identify(&samples), Ok(expected));
+    }
+
+    #[test]
+    fn ambiguous() {
+        // Both ops could be either of Addi and Mulr, so elimination alone gets nowhere.
+        let samples = [
+            sample([3, 2, 1, 1], [7, 2, 1, 2], [3, 2, 2, 1]),
+            sample([3, 2, 2, 1], [7, 2, 2, 0], [4, 2, 2, 1]),
+            sample([3, 2, 1, 1], [8, 2, 1, 2], [3, 2, 2, 1]),
+            sample([3, 2, 2, 1], [8, 2, 2, 0], [4, 2, 2, 1]),
+        ];
+        match identify(&samples) {
+            Err(IdentifyError::Ambiguous {
+                mut mappings,
+                truncated: false,
+            }) => {
+                mappings.sort_by_key(|mapping| mapping[&7] == Mulr);
+                assert_eq!(mappings[0][&7], Addi);
+                assert_eq!(mappings[0][&8], Mulr);
+                assert_eq!(mappings[1][&7], Mulr);
+                assert_eq!(mappings[1][&8], Addi);
+                assert_eq!(mappings.len(), 2);
+            }
+            other => panic!("expected two mappings, got {:?}", other),
+        }
+
+        // One more sample settles it.
+        let mut samples = samples.to_vec();
+        samples.push(sample([0, 5, 0, 3], [8, 1, 3, 2], [0, 5, 15, 3]));
+        let expected: HashMap<Op, Opcode> = vec![(7, Addi), (8, Mulr)].into_iter().collect();
+        assert_eq!(identify(&samples), Ok(expected));
+    }
+
+    #[test]
+    fn contradictions() {
+        // Op 4 can't be Seti and also leave r2 alone.
+        let samples = [
+            sample([3, 2, 1, 1], [4, 2, 5, 2], [3, 2, 2, 1]),
+            sample([0, 0, 0, 0], [4, 2, 1, 0], [0, 0, 0, 0]),
+        ];
+        assert_eq!(
+            identify(&samples),
+            Err(IdentifyError::Contradiction { sample: 1 })
+        );
+
+        // Each op is fine on its own, but they can't both be Seti.
+        let samples = [
+            sample([3, 2, 1, 1], [4, 2, 5, 2], [3, 2, 2, 1]),
+            sample([0, 0, 0, 0], [6, 0, 9, 3], [0, 0, 0, 0]),
+            sample([3, 2, 1, 1], [6, 2, 5, 2], [3, 2, 2, 1]),
+        ];
+        assert_eq!(
+            identify(&samples),
+            Err(IdentifyError::Contradiction { sample: 2 })
+        );
+    }
+}
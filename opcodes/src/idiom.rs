@@ -0,0 +1,360 @@
+use std::convert::TryFrom;
+
+use crate::Opcode::*;
+use crate::{Fault, Instruction, Machine, Op, Opcode, Program, Register, Status};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    // for j in j..=n { if i * j == n { acc += i } }
+    FactorSearch,
+    // for i in 1..=n { for j in 1..=n { if i * j == n { acc += i } } }
+    SumOfDivisors,
+}
+
+// Which register plays which part in an idiom.  `t` is the scratch register that holds
+// comparison results.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Roles {
+    pub i: usize,
+    pub j: usize,
+    pub t: usize,
+    pub n: usize,
+    pub acc: usize,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Idiom {
+    pub kind: Kind,
+    pub start: usize,
+    // the first instruction after the loop
+    pub end: usize,
+    pub roles: Roles,
+}
+
+#[derive(Clone, Copy)]
+enum Role {
+    Ip,
+    I,
+    J,
+    T,
+    N,
+    Acc,
+}
+
+#[derive(Clone, Copy)]
+enum Arg {
+    Reg(Role),
+    Imm(Op),
+    Any,
+    // "seti X _ ip" that lands on the instruction at this offset from the start of the idiom
+    Goto(usize),
+}
+
+struct Pattern(Opcode, Arg, Arg, Arg);
+
+use self::Arg::*;
+
+const FACTOR_SEARCH: &[Pattern] = &[
+    Pattern(Mulr, Reg(Role::I), Reg(Role::J), Reg(Role::T)),
+    Pattern(Eqrr, Reg(Role::T), Reg(Role::N), Reg(Role::T)),
+    Pattern(Addr, Reg(Role::T), Reg(Role::Ip), Reg(Role::Ip)),
+    Pattern(Addi, Reg(Role::Ip), Imm(1), Reg(Role::Ip)),
+    Pattern(Addr, Reg(Role::I), Reg(Role::Acc), Reg(Role::Acc)),
+    Pattern(Addi, Reg(Role::J), Imm(1), Reg(Role::J)),
+    Pattern(Gtrr, Reg(Role::J), Reg(Role::N), Reg(Role::T)),
+    Pattern(Addr, Reg(Role::T), Reg(Role::Ip), Reg(Role::Ip)),
+    Pattern(Seti, Goto(0), Any, Reg(Role::Ip)),
+];
+
+const SUM_OF_DIVISORS: &[Pattern] = &[
+    Pattern(Seti, Imm(1), Any, Reg(Role::I)),
+    Pattern(Seti, Imm(1), Any, Reg(Role::J)),
+    Pattern(Mulr, Reg(Role::I), Reg(Role::J), Reg(Role::T)),
+    Pattern(Eqrr, Reg(Role::T), Reg(Role::N), Reg(Role::T)),
+    Pattern(Addr, Reg(Role::T), Reg(Role::Ip), Reg(Role::Ip)),
+    Pattern(Addi, Reg(Role::Ip), Imm(1), Reg(Role::Ip)),
+    Pattern(Addr, Reg(Role::I), Reg(Role::Acc), Reg(Role::Acc)),
+    Pattern(Addi, Reg(Role::J), Imm(1), Reg(Role::J)),
+    Pattern(Gtrr, Reg(Role::J), Reg(Role::N), Reg(Role::T)),
+    Pattern(Addr, Reg(Role::T), Reg(Role::Ip), Reg(Role::Ip)),
+    Pattern(Seti, Goto(2), Any, Reg(Role::Ip)),
+    Pattern(Addi, Reg(Role::I), Imm(1), Reg(Role::I)),
+    Pattern(Gtrr, Reg(Role::I), Reg(Role::N), Reg(Role::T)),
+    Pattern(Addr, Reg(Role::T), Reg(Role::Ip), Reg(Role::Ip)),
+    Pattern(Seti, Goto(1), Any, Reg(Role::Ip)),
+];
+
+type Bindings = [Option<usize>; 6];
+
+fn bind(bindings: &mut Bindings, arg: Arg, value: Op, start: usize) -> bool {
+    match arg {
+        Reg(role) => match bindings[role as usize] {
+            Some(register) => register == value,
+            None if bindings.contains(&Some(value)) => false,
+            None => {
+                bindings[role as usize] = Some(value);
+                true
+            }
+        },
+        Imm(expected) => expected == value,
+        Any => true,
+        Goto(offset) => value.checked_add(1) == Some(start + offset),
+    }
+}
+
+fn match_one(
+    pattern: &Pattern,
+    instruction: Instruction,
+    start: usize,
+    bindings: Bindings,
+) -> Option<Bindings> {
+    let Pattern(opcode, pa, pb, pc) = *pattern;
+    let Instruction(actual, a, b, c) = instruction;
+    if opcode != actual {
+        return None;
+    }
+
+    let commutative = matches!(opcode, Addr | Mulr | Banr | Borr | Eqrr);
+    let orders: &[(Op, Op)] = if commutative {
+        &[(a, b), (b, a)]
+    } else {
+        &[(a, b)]
+    };
+
+    orders.iter().find_map(|&(a, b)| {
+        let mut attempt = bindings;
+        if bind(&mut attempt, pa, a, start)
+            && bind(&mut attempt, pb, b, start)
+            && bind(&mut attempt, pc, c, start)
+        {
+            Some(attempt)
+        } else {
+            None
+        }
+    })
+}
+
+fn match_at(program: &Program, start: usize, kind: Kind) -> Option<Idiom> {
+    let template = match kind {
+        Kind::FactorSearch => FACTOR_SEARCH,
+        Kind::SumOfDivisors => SUM_OF_DIVISORS,
+    };
+
+    let mut bindings = [None; 6];
+    bindings[Role::Ip as usize] = Some(program.ip_binding?);
+
+    for (offset, pattern) in template.iter().enumerate() {
+        let instruction = *program.instructions.get(start + offset)?;
+        bindings = match_one(pattern, instruction, start, bindings)?;
+    }
+
+    Some(Idiom {
+        kind,
+        start,
+        end: start + template.len(),
+        roles: Roles {
+            i: bindings[Role::I as usize]?,
+            j: bindings[Role::J as usize]?,
+            t: bindings[Role::T as usize]?,
+            n: bindings[Role::N as usize]?,
+            acc: bindings[Role::Acc as usize]?,
+        },
+    })
+}
+
+pub fn recognize(program: &Program) -> Vec<Idiom> {
+    (0..program.instructions.len())
+        .flat_map(|start| {
+            [Kind::SumOfDivisors, Kind::FactorSearch]
+                .iter()
+                .filter_map(move |&kind| match_at(program, start, kind))
+        })
+        .collect()
+}
+
+fn sum_of_divisors(n: u128) -> u128 {
+    (1..)
+        .take_while(|d| d * d <= n)
+        .filter(|&d| n.is_multiple_of(d))
+        .map(|d| if d * d == n { d } else { d + n / d })
+        .sum()
+}
+
+impl Idiom {
+    // Jumps the machine straight past the loop, if it is sitting at the start of it and the closed
+    // form is known to agree with what the loop would have computed.  Returns whether it did.
+    // Skipped instructions are not counted in Machine::steps().
+    pub fn apply<R: Register, const N: usize>(&self, machine: &mut Machine<R, N>) -> bool {
+        if machine.ip() != self.start {
+            return false;
+        }
+        self.closed_form(machine).is_some()
+    }
+
+    fn closed_form<R: Register, const N: usize>(&self, machine: &mut Machine<R, N>) -> Option<()> {
+        let Roles { i, j, t, n, acc } = self.roles;
+        let ip = machine.ip_binding()?;
+        let regs = machine.registers;
+        if [i, j, t, n, acc].iter().any(|&register| register >= N) {
+            // the interpreter will fault on this, and should be the one to report it
+            return None;
+        }
+
+        let (i_start, j_start) = match self.kind {
+            Kind::FactorSearch => (regs[i], regs[j]),
+            Kind::SumOfDivisors => (R::ONE, R::ONE),
+        };
+
+        // Every product the loop computes is at most this, so if it fits then the loop can't
+        // have overflowed, and the closed form below is exact.
+        let limit = std::cmp::max(regs[n], j_start);
+        std::cmp::max(limit, i_start).checked_mul(limit)?;
+
+        let wide = |value: R| -> Option<u128> { Some(value.try_into().ok()? as u128) };
+        let narrow = |value: u128| -> Option<R> { R::try_from(usize::try_from(value).ok()?).ok() };
+        let (i_value, j_value, n_value) = (wide(i_start)?, wide(j_start)?, wide(regs[n])?);
+        let last = std::cmp::max(j_value, n_value);
+
+        let (added, i_end) = match self.kind {
+            Kind::FactorSearch => {
+                let found = i_value != 0
+                    && n_value.is_multiple_of(i_value)
+                    && (j_value..=last).contains(&(n_value / i_value));
+                (if found { i_value } else { 0 }, i_value)
+            }
+            Kind::SumOfDivisors => (sum_of_divisors(n_value), std::cmp::max(n_value, 1) + 1),
+        };
+
+        let acc_value = narrow(wide(regs[acc])?.checked_add(added)?)?;
+        let i_value = narrow(i_end)?;
+        let j_value = narrow(last + 1)?;
+
+        let mut after = regs;
+        after[acc] = acc_value;
+        after[i] = i_value;
+        after[j] = j_value;
+        after[t] = R::ONE;
+        after[ip] = narrow(self.end as u128 - 1)?;
+
+        machine.registers = after;
+        machine.set_ip(self.end);
+        Some(())
+    }
+}
+
+// Like Machine::run(), but replaces any of the given idioms with their closed form whenever
+// execution reaches the start of one.  `on_apply` is told each time that happens.
+pub fn run<R, F, const N: usize>(
+    machine: &mut Machine<R, N>,
+    idioms: &[Idiom],
+    mut on_apply: F,
+) -> Result<(), Fault>
+where
+    R: Register,
+    F: FnMut(&Idiom),
+{
+    loop {
+        let ip = machine.ip();
+        if let Some(idiom) = idioms
+            .iter()
+            .find(|idiom| idiom.start == ip && idiom.apply(machine))
+        {
+            on_apply(idiom);
+            continue;
+        }
+
+        if machine.step()? == Status::Halted {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parse_program, Reg};
+
+    // The divisor-summing core of an AoC 2018 day 19 program, with register 5 as the input.
+    const DIVISORS: &str = "#ip 3
+seti 1 0 2
+seti 1 0 4
+mulr 2 4 1
+eqrr 1 5 1
+addr 1 3 3
+addi 3 1 3
+addr 2 0 0
+addi 4 1 4
+gtrr 4 5 1
+addr 3 1 3
+seti 1 0 3
+addi 2 1 2
+gtrr 2 5 1
+addr 1 3 3
+seti 0 0 3
+";
+
+    #[test]
+    fn recognize() {
+        let program = parse_program(DIVISORS).unwrap();
+        let roles = Roles {
+            i: 2,
+            j: 4,
+            t: 1,
+            n: 5,
+            acc: 0,
+        };
+
+        assert_eq!(
+            super::recognize(&program),
+            vec![
+                Idiom {
+                    kind: Kind::SumOfDivisors,
+                    start: 0,
+                    end: 15,
+                    roles
+                },
+                Idiom {
+                    kind: Kind::FactorSearch,
+                    start: 2,
+                    end: 11,
+                    roles
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn agrees_with_interpreter() {
+        let program = parse_program(DIVISORS).unwrap();
+        let idioms = super::recognize(&program);
+
+        for n in 0..40 {
+            let mut plain: Machine = Machine::from(program.clone());
+            plain.registers[5] = n;
+            plain.registers[0] = 7;
+            plain.run().unwrap();
+
+            for subset in &[&idioms[..], &idioms[..1], &idioms[1..]] {
+                let mut fast: Machine = Machine::from(program.clone());
+                fast.registers[5] = n;
+                fast.registers[0] = 7;
+                let mut applied = 0;
+                run(&mut fast, subset, |_| applied += 1).unwrap();
+
+                assert_eq!(fast.registers, plain.registers, "n = {}", n);
+                assert!(applied > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn declines_when_it_could_overflow() {
+        let program = parse_program(DIVISORS).unwrap();
+        let idioms = super::recognize(&program);
+
+        let mut machine: Machine = Machine::from(program);
+        machine.registers[5] = Reg::MAX;
+        assert!(!idioms[0].apply(&mut machine));
+        assert_eq!(machine.ip(), 0);
+    }
+}
@@ -1,6 +1,123 @@
+use std::convert::{TryFrom, TryInto};
+use std::ops::{BitAnd, BitOr};
+
+pub mod asm;
+pub mod decode;
+pub mod disasm;
+pub mod extended;
+pub mod flow;
+pub mod generate;
+pub mod identify;
+pub mod idiom;
+mod machine;
+pub mod optimize;
+mod parse;
+pub mod profile;
+pub mod symbolic;
+pub mod trace;
+pub mod transpile;
+
+pub use machine::{Fault, Machine, Status};
+pub use parse::{parse_program, parse_program_in, parse_samples, ParseError, SampleFile};
+
 pub type Reg = u64;
 pub type Op = usize;
 
+pub trait Register:
+    Copy
+    + Ord
+    + std::fmt::Debug
+    + BitAnd<Output = Self>
+    + BitOr<Output = Self>
+    + TryFrom<Op>
+    + TryInto<Op>
+{
+    const ZERO: Self;
+    const ONE: Self;
+
+    fn wrapping_add(self, other: Self) -> Self;
+    fn checked_add(self, other: Self) -> Option<Self>;
+    fn saturating_add(self, other: Self) -> Self;
+    fn wrapping_mul(self, other: Self) -> Self;
+    fn checked_mul(self, other: Self) -> Option<Self>;
+    fn saturating_mul(self, other: Self) -> Self;
+    fn checked_div(self, other: Self) -> Option<Self>;
+    fn checked_rem(self, other: Self) -> Option<Self>;
+    fn checked_shl(self, bits: u32) -> Option<Self>;
+    fn checked_shr(self, bits: u32) -> Option<Self>;
+}
+
+macro_rules! register {
+    ($($t:ty),*) => {
+        $(
+            impl Register for $t {
+                const ZERO: Self = 0;
+                const ONE: Self = 1;
+
+                fn wrapping_add(self, other: Self) -> Self {
+                    <$t>::wrapping_add(self, other)
+                }
+                fn checked_add(self, other: Self) -> Option<Self> {
+                    <$t>::checked_add(self, other)
+                }
+                fn saturating_add(self, other: Self) -> Self {
+                    <$t>::saturating_add(self, other)
+                }
+                fn wrapping_mul(self, other: Self) -> Self {
+                    <$t>::wrapping_mul(self, other)
+                }
+                fn checked_mul(self, other: Self) -> Option<Self> {
+                    <$t>::checked_mul(self, other)
+                }
+                fn saturating_mul(self, other: Self) -> Self {
+                    <$t>::saturating_mul(self, other)
+                }
+                fn checked_div(self, other: Self) -> Option<Self> {
+                    <$t>::checked_div(self, other)
+                }
+                fn checked_rem(self, other: Self) -> Option<Self> {
+                    <$t>::checked_rem(self, other)
+                }
+                fn checked_shl(self, bits: u32) -> Option<Self> {
+                    <$t>::checked_shl(self, bits)
+                }
+                fn checked_shr(self, bits: u32) -> Option<Self> {
+                    <$t>::checked_shr(self, bits)
+                }
+            }
+        )*
+    };
+}
+
+register!(u8, u16, u32, u64, u128, usize);
+
+// What `addr`/`addi`/`mulr`/`muli` do when the result doesn't fit in the register.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Arithmetic {
+    Wrapping,
+    #[default]
+    Checked,
+    Saturating,
+}
+
+impl Arithmetic {
+    pub fn add<R: Register>(self, a: R, b: R) -> Option<R> {
+        match self {
+            Arithmetic::Wrapping => Some(a.wrapping_add(b)),
+            Arithmetic::Checked => a.checked_add(b),
+            Arithmetic::Saturating => Some(a.saturating_add(b)),
+        }
+    }
+
+    pub fn mul<R: Register>(self, a: R, b: R) -> Option<R> {
+        match self {
+            Arithmetic::Wrapping => Some(a.wrapping_mul(b)),
+            Arithmetic::Checked => a.checked_mul(b),
+            Arithmetic::Saturating => Some(a.saturating_mul(b)),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Opcode {
     Addr,
@@ -27,75 +144,389 @@ pub const ALL_OPCODES: &[Opcode] = &[
     Addr, Addi, Mulr, Muli, Banr, Bani, Borr, Bori, Setr, Seti, Gtir, Gtri, Gtrr, Eqir, Eqri, Eqrr,
 ];
 
-pub fn eval_one<const N: usize>(
-    opcode: Opcode,
-    before: [Reg; N],
-    instruction: [Op; 4],
-) -> [Reg; N] {
-    let [_opcode, source_1_idx, source_2_idx, dest_idx] = instruction;
-
-    let source_1 = before.get(source_1_idx).cloned();
-    let source_2 = before.get(source_2_idx).cloned();
-
-    let source_1_imm = source_1_idx as Reg;
-    let source_2_imm = source_2_idx as Reg;
-
-    let result_value = match opcode {
-        Addr => source_1.unwrap() + source_2.unwrap(),
-        Addi => source_1.unwrap() + source_2_imm,
-        Mulr => source_1.unwrap() * source_2.unwrap(),
-        Muli => source_1.unwrap() * source_2_imm,
-        Banr => source_1.unwrap() & source_2.unwrap(),
-        Bani => source_1.unwrap() & source_2_imm,
-        Borr => source_1.unwrap() | source_2.unwrap(),
-        Bori => source_1.unwrap() | source_2_imm,
-        Setr => source_1.unwrap(),
-        Seti => source_1_imm,
-        Gtir => {
-            if source_1_imm > source_2.unwrap() {
-                1
-            } else {
-                0
-            }
+impl Opcode {
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Addr => "addr",
+            Addi => "addi",
+            Mulr => "mulr",
+            Muli => "muli",
+            Banr => "banr",
+            Bani => "bani",
+            Borr => "borr",
+            Bori => "bori",
+            Setr => "setr",
+            Seti => "seti",
+            Gtir => "gtir",
+            Gtri => "gtri",
+            Gtrr => "gtrr",
+            Eqir => "eqir",
+            Eqri => "eqri",
+            Eqrr => "eqrr",
         }
-        Gtri => {
-            if source_1.unwrap() > source_2_imm {
-                1
-            } else {
-                0
-            }
+    }
+}
+
+impl std::fmt::Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.mnemonic())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UnknownMnemonic(pub String);
+
+impl std::fmt::Display for UnknownMnemonic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "unknown opcode mnemonic {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownMnemonic {}
+
+impl std::str::FromStr for Opcode {
+    type Err = UnknownMnemonic;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ALL_OPCODES
+            .iter()
+            .cloned()
+            .find(|opcode| opcode.mnemonic() == s)
+            .ok_or_else(|| UnknownMnemonic(s.to_string()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Instruction<O = Opcode>(pub O, pub Op, pub Op, pub Op);
+
+impl<O: InstructionSet> std::fmt::Display for Instruction<O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let Instruction(opcode, a, b, c) = self;
+        write!(f, "{} {} {} {}", opcode.mnemonic(), a, b, c)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Program<O = Opcode> {
+    pub ip_binding: Option<usize>,
+    pub instructions: Vec<Instruction<O>>,
+}
+
+// Written out by hand: deriving it would demand a default opcode.
+impl<O> Default for Program<O> {
+    fn default() -> Self {
+        Program {
+            ip_binding: None,
+            instructions: Vec::new(),
         }
-        Gtrr => {
-            if source_1.unwrap() > source_2.unwrap() {
-                1
-            } else {
-                0
-            }
+    }
+}
+
+impl<O: InstructionSet> std::fmt::Display for Program<O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(binding) = self.ip_binding {
+            writeln!(f, "#ip {}", binding)?;
         }
-        Eqir => {
-            if source_1_imm == source_2.unwrap() {
-                1
-            } else {
-                0
-            }
+        for instruction in &self.instructions {
+            writeln!(f, "{}", instruction)?;
         }
-        Eqri => {
-            if source_1.unwrap() == source_2_imm {
-                1
-            } else {
-                0
-            }
+        Ok(())
+    }
+}
+
+// How an opcode interprets one of its two input operands.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Source {
+    Register,
+    Immediate,
+    Ignored,
+}
+
+impl Opcode {
+    pub fn sources(self) -> (Source, Source) {
+        use Source::*;
+
+        match self {
+            Addr | Mulr | Banr | Borr | Gtrr | Eqrr => (Register, Register),
+            Addi | Muli | Bani | Bori | Gtri | Eqri => (Register, Immediate),
+            Gtir | Eqir => (Immediate, Register),
+            Setr => (Register, Ignored),
+            Seti => (Immediate, Ignored),
         }
-        Eqrr => {
-            if source_1.unwrap() == source_2.unwrap() {
-                1
-            } else {
-                0
+    }
+
+    pub fn is_comparison(self) -> bool {
+        matches!(self, Gtir | Gtri | Gtrr | Eqir | Eqri | Eqrr)
+    }
+
+    // Computes the value this opcode would write, given its already-fetched inputs.  Returns None
+    // only if the arithmetic is Checked and the result overflows.
+    pub fn apply<R: Register>(self, arithmetic: Arithmetic, a: R, b: R) -> Option<R> {
+        let flag = |condition| if condition { R::ONE } else { R::ZERO };
+
+        Some(match self {
+            Addr | Addi => arithmetic.add(a, b)?,
+            Mulr | Muli => arithmetic.mul(a, b)?,
+            Banr | Bani => a & b,
+            Borr | Bori => a | b,
+            Setr | Seti => a,
+            Gtir | Gtri | Gtrr => flag(a > b),
+            Eqir | Eqri | Eqrr => flag(a == b),
+        })
+    }
+}
+
+// An opcode type and what each of its opcodes does.  The machine, the parsers and opcode
+// identification all work with any instruction set; Opcode, the sixteen from the puzzles, is the
+// default everywhere.  See extended::Extended for one that adds a few more.
+pub trait InstructionSet: Copy + Eq + std::hash::Hash + std::fmt::Debug + 'static {
+    // Every opcode in the set.  Its order is the order candidates are reported in.
+    const ALL: &'static [Self];
+
+    fn mnemonic(self) -> &'static str;
+    fn sources(self) -> (Source, Source);
+    // Computes the value this opcode would write, given its already-fetched inputs.  Returns None
+    // if the result doesn't fit in R or isn't defined at all.
+    fn apply<R: Register>(self, arithmetic: Arithmetic, a: R, b: R) -> Option<R>;
+    // Whether the opcode has a result for these inputs at all, like a division by anything but
+    // zero.  try_eval_one reports the ones that don't as Undefined, and any other failure of apply
+    // as an Overflow.
+    fn defined<R: Register>(self, _a: R, _b: R) -> bool {
+        true
+    }
+}
+
+impl InstructionSet for Opcode {
+    const ALL: &'static [Self] = ALL_OPCODES;
+
+    fn mnemonic(self) -> &'static str {
+        Opcode::mnemonic(self)
+    }
+
+    fn sources(self) -> (Source, Source) {
+        Opcode::sources(self)
+    }
+
+    fn apply<R: Register>(self, arithmetic: Arithmetic, a: R, b: R) -> Option<R> {
+        Opcode::apply(self, arithmetic, a, b)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Operand {
+    A,
+    B,
+    C,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error<O = Opcode> {
+    RegisterOutOfRange {
+        opcode: O,
+        operand: Operand,
+        index: Op,
+        width: usize,
+    },
+    ImmediateOutOfRange {
+        opcode: O,
+        operand: Operand,
+        value: Op,
+    },
+    Overflow {
+        opcode: O,
+    },
+    Undefined {
+        opcode: O,
+    },
+    // The "#ip" line names a register the machine doesn't have.
+    BindingOutOfRange {
+        index: usize,
+        width: usize,
+    },
+}
+
+impl<O: InstructionSet> std::fmt::Display for Error<O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::RegisterOutOfRange {
+                opcode,
+                operand,
+                index,
+                width,
+            } => write!(
+                f,
+                "{} operand {:?} names register {}, but there are only {}",
+                opcode.mnemonic(),
+                operand,
+                index,
+                width
+            ),
+            Error::ImmediateOutOfRange {
+                opcode,
+                operand,
+                value,
+            } => write!(
+                f,
+                "{} operand {:?} is {}, which does not fit in a register",
+                opcode.mnemonic(),
+                operand,
+                value
+            ),
+            Error::Overflow { opcode } => write!(f, "{} overflowed", opcode.mnemonic()),
+            Error::Undefined { opcode } => {
+                write!(f, "{} is undefined for its inputs", opcode.mnemonic())
             }
+            Error::BindingOutOfRange { index, width } => write!(
+                f,
+                "cannot bind the instruction pointer to register {} of {}",
+                index, width
+            ),
         }
+    }
+}
+
+impl<O: InstructionSet> std::error::Error for Error<O> {}
+
+pub fn try_eval_one<O: InstructionSet, R: Register, const N: usize>(
+    opcode: O,
+    before: [R; N],
+    instruction: [Op; 4],
+) -> Result<[R; N], Error<O>> {
+    try_eval_one_with(Arithmetic::default(), opcode, before, instruction)
+}
+
+pub fn try_eval_one_with<O: InstructionSet, R: Register, const N: usize>(
+    arithmetic: Arithmetic,
+    opcode: O,
+    before: [R; N],
+    instruction: [Op; 4],
+) -> Result<[R; N], Error<O>> {
+    let [_opcode, source_1_idx, source_2_idx, dest_idx] = instruction;
+
+    let out_of_range = |operand, index| Error::RegisterOutOfRange {
+        opcode,
+        operand,
+        index,
+        width: N,
+    };
+    let fetch = |source, operand, index: Op| match source {
+        Source::Register => before
+            .get(index)
+            .cloned()
+            .ok_or_else(|| out_of_range(operand, index)),
+        Source::Immediate => R::try_from(index).map_err(|_| Error::ImmediateOutOfRange {
+            opcode,
+            operand,
+            value: index,
+        }),
+        Source::Ignored => Ok(R::ZERO),
     };
 
+    let (source_1_kind, source_2_kind) = opcode.sources();
+    let source_1 = fetch(source_1_kind, Operand::A, source_1_idx)?;
+    let source_2 = fetch(source_2_kind, Operand::B, source_2_idx)?;
+
+    if !opcode.defined(source_1, source_2) {
+        return Err(Error::Undefined { opcode });
+    }
+    let result_value = opcode
+        .apply(arithmetic, source_1, source_2)
+        .ok_or(Error::Overflow { opcode })?;
+
     let mut result = before;
-    result[dest_idx] = result_value;
-    result
+    *result
+        .get_mut(dest_idx)
+        .ok_or_else(|| out_of_range(Operand::C, dest_idx))? = result_value;
+    Ok(result)
+}
+
+pub fn eval_one<O: InstructionSet, R: Register, const N: usize>(
+    opcode: O,
+    before: [R; N],
+    instruction: [Op; 4],
+) -> [R; N] {
+    try_eval_one(opcode, before, instruction).unwrap_or_else(|e| panic!("{}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn eval_sample() {
+        assert_eq!(eval_one(Mulr, [3u64, 2, 1, 1], [9, 2, 1, 2]), [3, 2, 2, 1]);
+        assert_eq!(eval_one(Addi, [3u64, 2, 1, 1], [9, 2, 1, 2]), [3, 2, 2, 1]);
+        assert_eq!(eval_one(Seti, [3u64, 2, 1, 1], [9, 2, 1, 2]), [3, 2, 2, 1]);
+    }
+
+    #[test]
+    fn mnemonics() {
+        for &opcode in ALL_OPCODES {
+            assert_eq!(opcode.to_string().parse(), Ok(opcode));
+        }
+        assert_eq!(
+            "movr".parse::<Opcode>(),
+            Err(UnknownMnemonic("movr".to_string()))
+        );
+    }
+
+    #[test]
+    fn arithmetic() {
+        let before: [u8; 2] = [200, 100];
+        let addr = [0, 0, 1, 0];
+
+        assert_eq!(
+            try_eval_one_with(Arithmetic::Wrapping, Addr, before, addr),
+            Ok([44, 100])
+        );
+        assert_eq!(
+            try_eval_one_with(Arithmetic::Saturating, Addr, before, addr),
+            Ok([255, 100])
+        );
+        assert_eq!(
+            try_eval_one_with(Arithmetic::Checked, Addr, before, addr),
+            Err(Error::Overflow { opcode: Addr })
+        );
+        assert_eq!(
+            try_eval_one_with(Arithmetic::Checked, Seti, before, [0, 256, 0, 1]),
+            Err(Error::ImmediateOutOfRange {
+                opcode: Seti,
+                operand: Operand::A,
+                value: 256,
+            })
+        );
+
+        let wide: [u128; 1] = [u64::MAX as u128];
+        assert_eq!(
+            try_eval_one(Muli, wide, [0, 0, 2, 0]),
+            Ok([u64::MAX as u128 * 2])
+        );
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert_eq!(
+            try_eval_one(Addr, [0u64; 4], [0, 1, 7, 2]),
+            Err(Error::RegisterOutOfRange {
+                opcode: Addr,
+                operand: Operand::B,
+                index: 7,
+                width: 4,
+            })
+        );
+        assert_eq!(
+            try_eval_one(Seti, [0u64; 4], [0, 1, 7, 4]),
+            Err(Error::RegisterOutOfRange {
+                opcode: Seti,
+                operand: Operand::C,
+                index: 4,
+                width: 4,
+            })
+        );
+        // immediates are never looked up in the register file
+        assert_eq!(
+            try_eval_one(Gtir, [0u64; 4], [0, 99, 0, 3]),
+            Ok([0, 0, 0, 1])
+        );
+    }
 }
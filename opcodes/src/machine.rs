@@ -0,0 +1,286 @@
+use crate::{
+    try_eval_one_with, Arithmetic, Error, Instruction, InstructionSet, Opcode, Program, Reg,
+    Register,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    Running,
+    Halted,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Fault<O = Opcode> {
+    pub ip: usize,
+    pub error: Error<O>,
+}
+
+impl<O: InstructionSet> std::fmt::Display for Fault<O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "instruction {} faulted: {}", self.ip, self.error)
+    }
+}
+
+impl<O: InstructionSet> std::error::Error for Fault<O> {}
+
+// An elfcode CPU: N registers of type R, optionally with one of them bound to the instruction
+// pointer as described by a "#ip" line.  Programs may use any instruction set, not just the
+// standard sixteen opcodes.
+#[derive(Clone, Debug)]
+pub struct Machine<R = Reg, const N: usize = 6, O = Opcode> {
+    pub registers: [R; N],
+    pub arithmetic: Arithmetic,
+    program: Vec<Instruction<O>>,
+    ip_binding: Option<usize>,
+    ip: usize,
+    steps: u64,
+}
+
+impl<R: Register, const N: usize, O: InstructionSet> Machine<R, N, O> {
+    // Panics if the instruction pointer is bound to a register past N; use try_new for bindings
+    // that come from user input.
+    pub fn new(program: Vec<Instruction<O>>, ip_binding: Option<usize>) -> Self {
+        Machine::try_new(program, ip_binding).unwrap_or_else(|fault| panic!("{}", fault.error))
+    }
+
+    // A binding past N is reported as a fault at instruction 0, since the machine can't start.
+    pub fn try_new(
+        program: Vec<Instruction<O>>,
+        ip_binding: Option<usize>,
+    ) -> Result<Self, Fault<O>> {
+        if let Some(binding) = ip_binding {
+            if binding >= N {
+                return Err(Fault {
+                    ip: 0,
+                    error: Error::BindingOutOfRange {
+                        index: binding,
+                        width: N,
+                    },
+                });
+            }
+        }
+
+        Ok(Machine {
+            registers: [R::ZERO; N],
+            arithmetic: Arithmetic::default(),
+            program,
+            ip_binding,
+            ip: 0,
+            steps: 0,
+        })
+    }
+
+    pub fn program(&self) -> &[Instruction<O>] {
+        &self.program
+    }
+
+    pub fn ip_binding(&self) -> Option<usize> {
+        self.ip_binding
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn set_ip(&mut self, ip: usize) {
+        self.ip = ip;
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // Puts the instruction pointer back to an instruction that has already been executed, for
+    // the benefit of trace::Tracer, which restores the registers itself.
+    pub(crate) fn rewind(&mut self, ip: usize) {
+        self.ip = ip;
+        self.steps -= 1;
+    }
+
+    // The instruction that the next call to step() would execute.
+    pub fn current(&self) -> Option<Instruction<O>> {
+        self.program.get(self.ip).cloned()
+    }
+
+    pub fn status(&self) -> Status {
+        if self.ip < self.program.len() {
+            Status::Running
+        } else {
+            Status::Halted
+        }
+    }
+
+    // Executes a single instruction, unless the machine has already halted.  On a fault, neither
+    // the registers nor the instruction pointer are changed.
+    pub fn step(&mut self) -> Result<Status, Fault<O>> {
+        let Instruction(opcode, a, b, c) = match self.current() {
+            Some(instruction) => instruction,
+            None => return Ok(Status::Halted),
+        };
+
+        let mut before = self.registers;
+        if let Some(binding) = self.ip_binding {
+            before[binding] = R::try_from(self.ip)
+                .ok()
+                .expect("instruction pointer does not fit in a register");
+        }
+
+        let after = try_eval_one_with(self.arithmetic, opcode, before, [0, a, b, c])
+            .map_err(|error| Fault { ip: self.ip, error })?;
+
+        let ip = match self.ip_binding {
+            Some(binding) => after[binding].try_into().ok(),
+            None => Some(self.ip),
+        };
+
+        self.registers = after;
+        // An instruction pointer too large to represent is just as out-of-bounds as any other.
+        self.ip = ip.and_then(|ip| ip.checked_add(1)).unwrap_or(usize::MAX);
+        self.steps += 1;
+
+        Ok(self.status())
+    }
+
+    pub fn run(&mut self) -> Result<(), Fault<O>> {
+        while self.step()? == Status::Running {}
+        Ok(())
+    }
+
+    // Runs until the predicate is true before executing an instruction, or until the machine
+    // halts, whichever comes first.  The predicate is checked before the very first step, too.
+    pub fn run_until<F>(&mut self, mut predicate: F) -> Result<Status, Fault<O>>
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        while self.status() == Status::Running {
+            if predicate(self) {
+                return Ok(Status::Running);
+            }
+            self.step()?;
+        }
+        Ok(Status::Halted)
+    }
+
+    pub fn run_for(&mut self, steps: u64) -> Result<Status, Fault<O>> {
+        for _ in 0..steps {
+            if self.step()? == Status::Halted {
+                return Ok(Status::Halted);
+            }
+        }
+        Ok(self.status())
+    }
+}
+
+impl<R: Register, const N: usize, O: InstructionSet> From<Program<O>> for Machine<R, N, O> {
+    fn from(program: Program<O>) -> Self {
+        Machine::new(program.instructions, program.ip_binding)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Opcode::*;
+
+    fn example() -> Machine {
+        Machine::new(
+            vec![
+                Instruction(Seti, 5, 0, 1),
+                Instruction(Seti, 6, 0, 2),
+                Instruction(Addi, 0, 1, 0),
+                Instruction(Addr, 1, 2, 3),
+                Instruction(Setr, 1, 0, 0),
+                Instruction(Seti, 8, 0, 4),
+                Instruction(Seti, 9, 0, 5),
+            ],
+            Some(0),
+        )
+    }
+
+    #[test]
+    fn run() {
+        let mut machine = example();
+        machine.run().unwrap();
+        assert_eq!(machine.registers, [6, 5, 6, 0, 0, 9]);
+        assert_eq!(machine.steps(), 5);
+        assert_eq!(machine.status(), Status::Halted);
+        assert_eq!(machine.step(), Ok(Status::Halted));
+        assert_eq!(machine.steps(), 5);
+    }
+
+    #[test]
+    fn step() {
+        let mut machine = example();
+        assert_eq!(machine.step(), Ok(Status::Running));
+        assert_eq!(machine.ip(), 1);
+        assert_eq!(machine.registers, [0, 5, 0, 0, 0, 0]);
+        assert_eq!(machine.step(), Ok(Status::Running));
+        assert_eq!(machine.step(), Ok(Status::Running));
+        assert_eq!(machine.ip(), 4);
+        assert_eq!(machine.registers, [3, 5, 6, 0, 0, 0]);
+    }
+
+    #[test]
+    fn run_until_and_for() {
+        let mut machine = example();
+        assert_eq!(machine.run_until(|m| m.ip() == 4), Ok(Status::Running));
+        assert_eq!(machine.steps(), 3);
+        assert_eq!(machine.run_until(|m| m.ip() == 4), Ok(Status::Running));
+        assert_eq!(machine.steps(), 3);
+
+        assert_eq!(machine.run_for(1), Ok(Status::Running));
+        assert_eq!(machine.ip(), 6);
+        assert_eq!(machine.run_for(10), Ok(Status::Halted));
+        assert_eq!(machine.steps(), 5);
+    }
+
+    #[test]
+    fn unbound() {
+        let mut machine: Machine<u32, 4> = Machine::new(
+            vec![Instruction(Seti, 3, 0, 0), Instruction(Addi, 0, 4, 1)],
+            None,
+        );
+        machine.run().unwrap();
+        assert_eq!(machine.registers, [3, 7, 0, 0]);
+        assert_eq!(machine.steps(), 2);
+    }
+
+    #[test]
+    fn binding_out_of_range() {
+        let machine = Machine::<Reg, 6>::try_new(vec![Instruction(Seti, 5, 0, 1)], Some(9));
+        let fault = machine.map(|_| ()).unwrap_err();
+        assert_eq!(
+            fault,
+            Fault {
+                ip: 0,
+                error: Error::BindingOutOfRange { index: 9, width: 6 },
+            }
+        );
+        assert_eq!(
+            fault.error.to_string(),
+            "cannot bind the instruction pointer to register 9 of 6"
+        );
+    }
+
+    #[test]
+    fn fault() {
+        let mut machine: Machine = Machine::new(
+            vec![Instruction(Seti, 3, 0, 1), Instruction(Addr, 1, 9, 1)],
+            Some(0),
+        );
+        assert_eq!(
+            machine.run(),
+            Err(Fault {
+                ip: 1,
+                error: Error::RegisterOutOfRange {
+                    opcode: Addr,
+                    operand: crate::Operand::B,
+                    index: 9,
+                    width: 6,
+                }
+            })
+        );
+        assert_eq!(machine.ip(), 1);
+        assert_eq!(machine.registers, [0, 3, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,527 @@
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+
+use crate::Opcode::*;
+use crate::{Arithmetic, Instruction, Op, Program, Reg, Source};
+
+// What constant propagation knows about a register before an instruction runs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Value {
+    Const(Reg),
+    // Either 0 or 1, as left by a comparison; enough to follow a conditional skip.
+    Flag,
+    Unknown,
+}
+
+impl Value {
+    fn from_possible(values: &BTreeSet<Reg>) -> Value {
+        match values.iter().collect::<Vec<_>>()[..] {
+            [&value] => Value::Const(value),
+            _ if values.iter().all(|&value| value <= 1) => Value::Flag,
+            _ => Value::Unknown,
+        }
+    }
+
+    fn possible(self) -> Option<BTreeSet<Reg>> {
+        match self {
+            Value::Const(value) => Some(std::iter::once(value).collect()),
+            Value::Flag => Some([0, 1].iter().cloned().collect()),
+            Value::Unknown => None,
+        }
+    }
+
+    fn meet(self, other: Value) -> Value {
+        match (self.possible(), other.possible()) {
+            (Some(mut a), Some(b)) => {
+                a.extend(b);
+                Value::from_possible(&a)
+            }
+            _ => Value::Unknown,
+        }
+    }
+}
+
+// The no-op that replaces a removed instruction.  Instructions are never deleted outright,
+// since that would move every jump target after them.
+fn nop(register: Op) -> Instruction {
+    Instruction(Setr, register, 0, register)
+}
+
+fn is_nop(Instruction(opcode, a, _, c): Instruction) -> bool {
+    opcode == Setr && a == c
+}
+
+struct Analysis<const N: usize> {
+    states: Vec<Option<[Value; N]>>,
+    successors: Vec<Vec<usize>>,
+    halts: Vec<bool>,
+}
+
+// Rewrites programs for N registers without changing what they compute or how many steps they
+// take.  Any write to the instruction pointer's register is a jump and is always kept.  The
+// passes assume the program never faults: an overflowing store that is never read may be
+// removed along with its fault.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Optimizer<const N: usize = 6> {
+    // Which registers are still of interest once the program halts.  Stores that can only be
+    // seen through the other registers' final values are dead.
+    pub live_at_halt: [bool; N],
+}
+
+impl<const N: usize> Default for Optimizer<N> {
+    fn default() -> Self {
+        Optimizer {
+            live_at_halt: [true; N],
+        }
+    }
+}
+
+impl<const N: usize> Optimizer<N> {
+    // Instructions naming a register past N fault when executed, so they're left alone.
+    fn valid(&self, Instruction(opcode, a, b, c): Instruction) -> bool {
+        let (source_a, source_b) = opcode.sources();
+        (source_a != Source::Register || a < N) && (source_b != Source::Register || b < N) && c < N
+    }
+
+    // The registers an instruction really depends on.  Reading the bound register yields the
+    // instruction's own index, not anything an earlier instruction stored.
+    fn reads(&self, program: &Program, instruction: Instruction) -> Vec<Op> {
+        let Instruction(opcode, a, b, _) = instruction;
+        if is_nop(instruction) {
+            return vec![];
+        }
+
+        let (source_a, source_b) = opcode.sources();
+        [(source_a, a), (source_b, b)]
+            .iter()
+            .filter(|&&(source, register)| {
+                source == Source::Register && Some(register) != program.ip_binding
+            })
+            .map(|&(_, register)| register)
+            .collect()
+    }
+
+    // Every value an instruction could write, given what's known on entry, or None if that's
+    // unbounded.  Combinations that would overflow fault instead of writing anything.
+    fn results(&self, state: &[Value; N], instruction: Instruction) -> Option<BTreeSet<Reg>> {
+        let Instruction(opcode, a, b, _) = instruction;
+        let (source_a, source_b) = opcode.sources();
+        let operand = |source, operand: Op| match source {
+            Source::Register => state[operand].possible(),
+            Source::Immediate => Reg::try_from(operand)
+                .ok()
+                .map(|value| std::iter::once(value).collect()),
+            Source::Ignored => Some(std::iter::once(0).collect()),
+        };
+
+        let (a, b) = match (operand(source_a, a), operand(source_b, b)) {
+            (Some(a), Some(b)) => (a, b),
+            _ if opcode.is_comparison() => return Some([0, 1].iter().cloned().collect()),
+            _ => return None,
+        };
+        Some(
+            a.iter()
+                .flat_map(|&a| {
+                    b.iter()
+                        .filter_map(move |&b| opcode.apply(Arithmetic::Checked, a, b))
+                })
+                .collect(),
+        )
+    }
+
+    // What's known about the registers on entry to each instruction (None if it's unreachable),
+    // where execution can go after it, and whether it can halt the program.
+    fn analyse(&self, program: &Program) -> Analysis<N> {
+        let len = program.instructions.len();
+        let mut states: Vec<Option<[Value; N]>> = vec![None; len];
+        let mut successors = vec![vec![]; len];
+        let mut halts = vec![false; len];
+        let mut work: BTreeSet<usize> = BTreeSet::new();
+        if len > 0 {
+            states[0] = Some([Value::Unknown; N]);
+            work.insert(0);
+        }
+
+        while let Some(index) = work.iter().next().cloned() {
+            work.remove(&index);
+            let instruction = program.instructions[index];
+            let mut state = states[index].expect("queued instructions have been reached");
+            if !self.valid(instruction) {
+                continue;
+            }
+
+            if let Some(ip) = program.ip_binding {
+                state[ip] = Reg::try_from(index).map_or(Value::Unknown, Value::Const);
+            }
+            let results = self.results(&state, instruction);
+            let Instruction(_, _, _, c) = instruction;
+            state[c] = results
+                .as_ref()
+                .map_or(Value::Unknown, Value::from_possible);
+
+            // An unrepresentable instruction pointer is past the end, like the Machine treats it.
+            let mut targets: Vec<usize> = match (program.ip_binding, &results) {
+                (Some(ip), Some(results)) if ip == c => results
+                    .iter()
+                    .map(|&result| {
+                        usize::try_from(result)
+                            .ok()
+                            .and_then(|result| result.checked_add(1))
+                            .unwrap_or(usize::MAX)
+                    })
+                    .collect(),
+                (Some(ip), None) if ip == c => (0..=len).collect(),
+                _ => vec![index + 1],
+            };
+            halts[index] = targets.iter().any(|&target| target >= len);
+            targets.retain(|&target| target < len);
+
+            for &target in &targets {
+                let merged = match states[target] {
+                    None => state,
+                    Some(old) => {
+                        let mut merged = old;
+                        for (merged, &new) in merged.iter_mut().zip(state.iter()) {
+                            *merged = merged.meet(new);
+                        }
+                        merged
+                    }
+                };
+                if states[target] != Some(merged) {
+                    states[target] = Some(merged);
+                    work.insert(target);
+                }
+            }
+            successors[index] = targets;
+        }
+
+        Analysis {
+            states,
+            successors,
+            halts,
+        }
+    }
+
+    // Replaces instructions whose result is always the same with a seti of that value, and
+    // register operands that always hold the same value with immediates.
+    pub fn propagate_constants(&self, program: &Program) -> Program {
+        let states = self.analyse(program).states;
+        let mut optimized = program.clone();
+
+        for (index, instruction) in optimized.instructions.iter_mut().enumerate() {
+            let mut state = match states[index] {
+                Some(state) if self.valid(*instruction) && !is_nop(*instruction) => state,
+                _ => continue,
+            };
+            if let Some(ip) = program.ip_binding {
+                state[ip] = Reg::try_from(index).map_or(Value::Unknown, Value::Const);
+            }
+
+            let Instruction(opcode, a, b, c) = *instruction;
+            let known = |register: Op| match state[register] {
+                Value::Const(value) => Op::try_from(value).ok(),
+                _ => None,
+            };
+            let folded = self.results(&state, *instruction).and_then(|results| {
+                match Value::from_possible(&results) {
+                    Value::Const(value) => Op::try_from(value).ok(),
+                    _ => None,
+                }
+            });
+
+            *instruction = match (folded, opcode) {
+                (Some(value), _) => Instruction(Seti, value, 0, c),
+                (None, Setr) => match known(a) {
+                    Some(a) => Instruction(Seti, a, 0, c),
+                    None => *instruction,
+                },
+                (None, Addr) | (None, Mulr) | (None, Banr) | (None, Borr) => {
+                    let immediate = match opcode {
+                        Addr => Addi,
+                        Mulr => Muli,
+                        Banr => Bani,
+                        _ => Bori,
+                    };
+                    match (known(a), known(b)) {
+                        (_, Some(b)) => Instruction(immediate, a, b, c),
+                        (Some(a), None) => Instruction(immediate, b, a, c),
+                        (None, None) => *instruction,
+                    }
+                }
+                (None, Gtrr) | (None, Eqrr) => {
+                    let (register_immediate, immediate_register) = match opcode {
+                        Gtrr => (Gtri, Gtir),
+                        _ => (Eqri, Eqir),
+                    };
+                    match (known(a), known(b)) {
+                        (_, Some(b)) => Instruction(register_immediate, a, b, c),
+                        (Some(a), None) => Instruction(immediate_register, a, b, c),
+                        (None, None) => *instruction,
+                    }
+                }
+                (None, _) => *instruction,
+            };
+        }
+
+        optimized
+    }
+
+    // Replaces stores to registers that are always overwritten before they're next read with
+    // no-ops.  Unreachable instructions are left as they are.
+    pub fn eliminate_dead_stores(&self, program: &Program) -> Program {
+        let Analysis {
+            states,
+            successors,
+            halts,
+        } = self.analyse(program);
+        let len = program.instructions.len();
+
+        let mut live_in = vec![[false; N]; len];
+        let mut live_out = vec![[false; N]; len];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for index in (0..len).rev() {
+                let instruction = program.instructions[index];
+                if states[index].is_none() || !self.valid(instruction) {
+                    continue;
+                }
+
+                let mut out = [false; N];
+                if halts[index] {
+                    out = self.live_at_halt;
+                }
+                for &target in &successors[index] {
+                    for (out, &live) in out.iter_mut().zip(live_in[target].iter()) {
+                        *out |= live;
+                    }
+                }
+
+                let mut live = out;
+                if !is_nop(instruction) {
+                    let Instruction(_, _, _, c) = instruction;
+                    live[c] = false;
+                }
+                for register in self.reads(program, instruction) {
+                    live[register] = true;
+                }
+
+                if live != live_in[index] || out != live_out[index] {
+                    live_in[index] = live;
+                    live_out[index] = out;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut optimized = program.clone();
+        for (index, instruction) in optimized.instructions.iter_mut().enumerate() {
+            let Instruction(_, _, _, c) = *instruction;
+            if states[index].is_some()
+                && self.valid(*instruction)
+                && Some(c) != program.ip_binding
+                && !live_out[index][c]
+            {
+                *instruction = nop(c);
+            }
+        }
+        optimized
+    }
+
+    // Rewrites instructions with an identity or absorbing operand into cheaper equivalents, down
+    // to a no-op for things like `muli rX 1 rX`.
+    pub fn reduce_strength(&self, program: &Program) -> Program {
+        let mut optimized = program.clone();
+        for instruction in optimized.instructions.iter_mut() {
+            if !self.valid(*instruction) {
+                continue;
+            }
+
+            let Instruction(opcode, a, b, c) = *instruction;
+            *instruction = match (opcode, b) {
+                (Muli, 1) | (Addi, 0) | (Bori, 0) => Instruction(Setr, a, 0, c),
+                (Muli, 0) | (Bani, 0) => Instruction(Seti, 0, 0, c),
+                (Muli, 2) => Instruction(Addr, a, a, c),
+                (Banr, _) | (Borr, _) if a == b => Instruction(Setr, a, 0, c),
+                (Gtrr, _) if a == b => Instruction(Seti, 0, 0, c),
+                (Eqrr, _) if a == b => Instruction(Seti, 1, 0, c),
+                // The second operand is ignored, so make equivalent instructions look the same.
+                (Setr, _) | (Seti, _) => Instruction(opcode, a, 0, c),
+                _ => *instruction,
+            };
+        }
+        optimized
+    }
+
+    // Runs every pass until none of them changes anything.
+    pub fn optimize(&self, program: &Program) -> Program {
+        let mut program = program.clone();
+        loop {
+            let optimized = self.eliminate_dead_stores(
+                &self.reduce_strength(&self.propagate_constants(&self.reduce_strength(&program))),
+            );
+            if optimized == program {
+                return program;
+            }
+            program = optimized;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::generate::XorShift;
+    use crate::{try_eval_one, ALL_OPCODES};
+
+    #[test]
+    fn passes() {
+        let program = crate::parse_program(
+            "#ip 5
+seti 3 0 1
+muli 1 1 1
+addr 1 1 2
+seti 9 0 3
+mulr 2 0 4
+",
+        )
+        .unwrap();
+        let optimizer: Optimizer = Optimizer::default();
+
+        let reduced = optimizer.reduce_strength(&program);
+        assert_eq!(reduced.instructions[1], nop(1));
+
+        let propagated = optimizer.propagate_constants(&reduced);
+        assert_eq!(
+            propagated.to_string(),
+            "#ip 5
+seti 3 0 1
+setr 1 0 1
+seti 6 0 2
+seti 9 0 3
+muli 0 6 4
+"
+        );
+
+        // r3 is overwritten before the program halts, and only r4 is wanted at the end.
+        let optimizer = Optimizer {
+            live_at_halt: [false, false, false, false, true, false],
+        };
+        assert_eq!(
+            optimizer.optimize(&program).to_string(),
+            "#ip 5
+setr 1 0 1
+setr 1 0 1
+setr 2 0 2
+setr 3 0 3
+muli 0 6 4
+"
+        );
+    }
+
+    #[test]
+    fn conditional_skip() {
+        // The flag in r2 only ever sends control to 3 or 4, rather than anywhere at all.
+        let program = crate::parse_program(
+            "#ip 5
+seti 7 0 1
+gtri 0 5 2
+addr 2 5 5
+seti 1 0 1
+addr 1 1 3
+",
+        )
+        .unwrap();
+        let optimizer: Optimizer = Optimizer::default();
+        let analysis = optimizer.analyse(&program);
+        assert_eq!(analysis.successors[2], vec![3, 4]);
+        assert!(!analysis.halts[2]);
+        assert_eq!(analysis.states[0], Some([Value::Unknown; 6]));
+        assert_eq!(analysis.states[4].unwrap()[2], Value::Flag);
+
+        let propagated = optimizer.propagate_constants(&program);
+        assert_eq!(propagated.instructions[2], Instruction(Addi, 2, 2, 5));
+        assert_eq!(propagated.instructions[4], program.instructions[4]);
+    }
+
+    // Runs a program one `try_eval_one` at a time, giving up on faults and on anything that
+    // hasn't halted within the step limit.
+    fn run(program: &Program, mut registers: [Reg; 4], limit: u64) -> Option<([Reg; 4], u64)> {
+        let mut ip = 0;
+        for steps in 0..limit {
+            let Instruction(opcode, a, b, c) = match program.instructions.get(ip) {
+                Some(&instruction) => instruction,
+                None => return Some((registers, steps)),
+            };
+            if let Some(binding) = program.ip_binding {
+                registers[binding] = ip as Reg;
+            }
+            registers = try_eval_one(opcode, registers, [0, a, b, c]).ok()?;
+            ip = match program.ip_binding {
+                Some(binding) => usize::try_from(registers[binding]).ok()?.checked_add(1)?,
+                None => ip + 1,
+            };
+        }
+        None
+    }
+
+    fn random_program(rng: &mut XorShift) -> Program {
+        let ip_binding = match rng.below(3) {
+            0 => None,
+            _ => Some(rng.below(4) as usize),
+        };
+        let len = 2 + rng.below(10) as usize;
+        let instructions = (0..len)
+            .map(|_| {
+                let opcode = ALL_OPCODES[rng.below(ALL_OPCODES.len() as u64) as usize];
+                let a = rng.below(4) as usize;
+                let b = rng.below(4) as usize;
+                Instruction(opcode, a, b, rng.below(4) as usize)
+            })
+            .collect();
+        Program {
+            ip_binding,
+            instructions,
+        }
+    }
+
+    #[test]
+    fn equivalence() {
+        let mut rng = XorShift::new(0x2018_1219);
+        let mut compared = 0;
+
+        for _ in 0..2000 {
+            let program = random_program(&mut rng);
+            let live_at_halt = [rng.below(2) == 0, true, rng.below(2) == 0, true];
+            let optimizer = Optimizer { live_at_halt };
+            let optimized = optimizer.optimize(&program);
+            assert_eq!(optimized.instructions.len(), program.instructions.len());
+
+            for _ in 0..10 {
+                let seed = [rng.below(6), rng.below(6), rng.below(6), rng.below(6)];
+                let (expected, steps) = match run(&program, seed, 200) {
+                    Some(result) => result,
+                    None => continue,
+                };
+                let (actual, optimized_steps) = run(&optimized, seed, 200).unwrap_or_else(|| {
+                    panic!("{}became\n{}faulted on {:?}", program, optimized, seed)
+                });
+
+                for register in 0..4 {
+                    assert!(
+                        !live_at_halt[register] || expected[register] == actual[register],
+                        "{}became\n{}which disagrees on r{} for {:?}",
+                        program,
+                        optimized,
+                        register,
+                        seed
+                    );
+                }
+                assert_eq!(steps, optimized_steps);
+                compared += 1;
+            }
+        }
+
+        assert!(compared > 5000, "only {} runs halted", compared);
+    }
+}
@@ -0,0 +1,405 @@
+use nom::bytes::complete::tag;
+use nom::character::complete::{alpha1, digit1, space0, space1};
+use nom::combinator::{all_consuming, map_res, verify};
+use nom::error::{context, VerboseError, VerboseErrorKind};
+use nom::sequence::{delimited, preceded, terminated, tuple};
+use nom::Parser;
+
+use crate::identify::Sample;
+use crate::{Instruction, InstructionSet, Op, Program, Reg, ALL_OPCODES};
+
+type IResult<'a, T> = nom::IResult<&'a str, T, VerboseError<&'a str>>;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseError {
+    // both 1-based, like an editor would show them
+    pub line: usize,
+    pub column: usize,
+    pub expected: &'static str,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: expected {}",
+            self.line, self.column, self.expected
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn integer(input: &str) -> IResult<'_, Op> {
+    context("an integer", map_res(digit1, str::parse::<Op>))(input)
+}
+
+fn opcode<'a, O: InstructionSet>(opcodes: &[O], input: &'a str) -> IResult<'a, O> {
+    let lookup = |mnemonic| {
+        opcodes
+            .iter()
+            .cloned()
+            .find(|opcode| opcode.mnemonic() == mnemonic)
+            .ok_or(())
+    };
+    context("an opcode mnemonic", map_res(alpha1, lookup))(input)
+}
+
+// Every machine in the puzzles has six registers, so a header naming any other can't be run.
+const REGISTERS: usize = 6;
+
+fn binding(input: &str) -> IResult<'_, usize> {
+    let register = context(
+        "a register number below 6",
+        verify(integer, |&index| index < REGISTERS),
+    );
+    preceded(tuple((tag("#ip"), space1)), register)(input)
+}
+
+fn instruction<'a, O: InstructionSet>(
+    opcodes: &[O],
+    input: &'a str,
+) -> IResult<'a, Instruction<O>> {
+    tuple((
+        |input| opcode(opcodes, input),
+        preceded(space1, integer),
+        preceded(space1, integer),
+        preceded(space1, integer),
+    ))
+    .map(|(op, a, b, c)| Instruction(op, a, b, c))
+    .parse(input)
+}
+
+fn numbers(input: &str) -> IResult<'_, [Op; 4]> {
+    let next = || context("an integer", preceded(space1, integer));
+    tuple((integer, next(), next(), next()))
+        .map(|(a, b, c, d)| [a, b, c, d])
+        .parse(input)
+}
+
+fn value(input: &str) -> IResult<'_, Reg> {
+    context("an integer", map_res(digit1, str::parse::<Reg>))(input)
+}
+
+// "[3, 2, 1, 1]"
+fn registers(input: &str) -> IResult<'_, [Reg; 4]> {
+    let comma = || context("a comma", tuple((space0, tag(","), space0)));
+    delimited(
+        tuple((context("a register list", tag("[")), space0)),
+        tuple((
+            value,
+            preceded(comma(), value),
+            preceded(comma(), value),
+            preceded(comma(), value),
+        )),
+        tuple((space0, context("a closing bracket", tag("]")))),
+    )
+    .map(|(a, b, c, d)| [a, b, c, d])
+    .parse(input)
+}
+
+fn state<'a>(label: &'static str) -> impl FnMut(&'a str) -> IResult<'a, [Reg; 4]> {
+    preceded(tuple((tag(label), space0)), registers)
+}
+
+fn line<'a, T, F>(parser: F, input: &'a str) -> IResult<'a, T>
+where
+    F: Parser<&'a str, T, VerboseError<&'a str>>,
+{
+    let end = context("the end of the line", space0);
+    all_consuming(terminated(preceded(space0, parser), end))(input)
+}
+
+fn error_at(line_number: usize, line: &str, error: nom::Err<VerboseError<&str>>) -> ParseError {
+    let errors = match error {
+        nom::Err::Error(e) | nom::Err::Failure(e) => e.errors,
+        nom::Err::Incomplete(_) => vec![],
+    };
+
+    let rest = errors.first().map(|&(rest, _)| rest).unwrap_or("");
+    let expected = errors
+        .iter()
+        .find_map(|(_, kind)| match kind {
+            VerboseErrorKind::Context(expected) => Some(*expected),
+            _ => None,
+        })
+        .unwrap_or("the end of the line");
+
+    ParseError {
+        line: line_number,
+        column: line.len() - rest.len() + 1,
+        expected,
+    }
+}
+
+// Parses a whole elfcode listing: an optional "#ip N" header, N naming one of the six registers,
+// followed by one instruction per line.  Blank lines are ignored, and either line ending is
+// accepted.
+pub fn parse_program(input: &str) -> Result<Program, ParseError> {
+    parse_program_in(ALL_OPCODES, input)
+}
+
+// The same, accepting the mnemonics of `opcodes` instead of the standard sixteen.
+pub fn parse_program_in<O: InstructionSet>(
+    opcodes: &[O],
+    input: &str,
+) -> Result<Program<O>, ParseError> {
+    let mut ip_binding = None;
+    let mut instructions = Vec::new();
+
+    for (line_number, text) in input.lines().enumerate().map(|(i, l)| (i + 1, l)) {
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        if ip_binding.is_none() && instructions.is_empty() && text.trim_start().starts_with('#') {
+            let (_, bound) = line(context("an #ip header", binding), text)
+                .map_err(|e| error_at(line_number, text, e))?;
+            ip_binding = Some(bound);
+            continue;
+        }
+
+        let (_, parsed) = line(|input| instruction(opcodes, input), text)
+            .map_err(|e| error_at(line_number, text, e))?;
+        instructions.push(parsed);
+    }
+
+    Ok(Program {
+        ip_binding,
+        instructions,
+    })
+}
+
+// A day 16 input: samples of instructions with unknown opcode numbers, followed by a program
+// written with the same numbers.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SampleFile {
+    pub samples: Vec<Sample>,
+    pub program: Vec<[Op; 4]>,
+}
+
+// Writes the file back out the way the puzzle input looks, with three blank lines between the
+// samples and the program.
+impl std::fmt::Display for SampleFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let registers = |r: [Reg; 4]| format!("[{}, {}, {}, {}]", r[0], r[1], r[2], r[3]);
+        for sample in &self.samples {
+            let [op, a, b, c] = sample.instruction;
+            writeln!(f, "Before: {}", registers(sample.before))?;
+            writeln!(f, "{} {} {} {}", op, a, b, c)?;
+            writeln!(f, "After:  {}", registers(sample.after))?;
+            writeln!(f)?;
+        }
+        writeln!(f)?;
+        writeln!(f)?;
+        for [op, a, b, c] in &self.program {
+            writeln!(f, "{} {} {} {}", op, a, b, c)?;
+        }
+        Ok(())
+    }
+}
+
+// Parses a whole day 16 input.  Each sample is a "Before:" line, an instruction and an "After:"
+// line; the program starts at the first instruction that isn't part of a sample.  Any number of
+// blank lines may separate anything, and either line ending is accepted.
+pub fn parse_samples(input: &str) -> Result<SampleFile, ParseError> {
+    let mut file = SampleFile::default();
+    let end = input.lines().count() + 1;
+    let mut lines = input
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l))
+        .filter(|(_, l)| !l.trim().is_empty());
+
+    while let Some((line_number, text)) = lines.next() {
+        let parse = |parser, line_number, text| {
+            line(parser, text)
+                .map(|(_, parsed)| parsed)
+                .map_err(|e| error_at(line_number, text, e))
+        };
+
+        if !file.program.is_empty() || !text.trim_start().starts_with("Before") {
+            file.program.push(parse(numbers, line_number, text)?);
+            continue;
+        }
+
+        let before = line(context("a Before: line", state("Before:")), text)
+            .map_err(|e| error_at(line_number, text, e))?
+            .1;
+        let mut next = |expected| {
+            lines.next().ok_or(ParseError {
+                line: end,
+                column: 1,
+                expected,
+            })
+        };
+        let (line_number, text) = next("an instruction")?;
+        let instruction = parse(numbers, line_number, text)?;
+        let (line_number, text) = next("an After: line")?;
+        let after = line(context("an After: line", state("After:")), text)
+            .map_err(|e| error_at(line_number, text, e))?
+            .1;
+
+        file.samples.push(Sample {
+            before,
+            instruction,
+            after,
+        });
+    }
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Opcode::*;
+
+    #[test]
+    fn example() {
+        let program = parse_program(
+            "#ip 0
+seti 5 0 1
+seti 6 0 2
+addi 0 1 0
+addr 1 2 3
+setr 1 0 0
+seti 8 0 4
+seti 9 0 5
+",
+        )
+        .expect("parsing failed");
+
+        assert_eq!(
+            program,
+            Program {
+                ip_binding: Some(0),
+                instructions: vec![
+                    Instruction(Seti, 5, 0, 1),
+                    Instruction(Seti, 6, 0, 2),
+                    Instruction(Addi, 0, 1, 0),
+                    Instruction(Addr, 1, 2, 3),
+                    Instruction(Setr, 1, 0, 0),
+                    Instruction(Seti, 8, 0, 4),
+                    Instruction(Seti, 9, 0, 5),
+                ]
+            }
+        );
+
+        assert_eq!(parse_program(&program.to_string()), Ok(program));
+    }
+
+    #[test]
+    fn crlf_and_no_header() {
+        assert_eq!(
+            parse_program("seti 5 0 1\r\n\r\nmulr 1 1 2\r\n"),
+            Ok(Program {
+                ip_binding: None,
+                instructions: vec![Instruction(Seti, 5, 0, 1), Instruction(Mulr, 1, 1, 2)],
+            })
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = |line, column, expected| {
+            Err(ParseError {
+                line,
+                column,
+                expected,
+            })
+        };
+
+        assert_eq!(
+            parse_program("#ip 0\nseti 5 0 1\nmovr 1 2 3\n"),
+            error(3, 1, "an opcode mnemonic")
+        );
+        assert_eq!(
+            parse_program("#ip 0\nseti 5 x 1\n"),
+            error(2, 8, "an integer")
+        );
+        assert_eq!(
+            parse_program("#ip 0\nseti 5 0 1 7\n"),
+            error(2, 12, "the end of the line")
+        );
+        assert_eq!(parse_program("#ipx 0\n"), error(1, 4, "an #ip header"));
+        assert_eq!(
+            parse_program("\n  #ip 9\nseti 5 0 1\n"),
+            error(2, 7, "a register number below 6")
+        );
+        assert_eq!(
+            parse_program("seti 5 0 1\n#ip 0\n"),
+            error(2, 1, "an opcode mnemonic")
+        );
+    }
+
+    #[test]
+    fn samples() {
+        let file = parse_samples(
+            "Before: [3, 2, 1, 1]\r
+9 2 1 2\r
+After:  [3, 2, 2, 1]\r
+\r
+Before: [0, 1, 2, 3]
+0 1 2 3
+After:  [0,1,2,3]
+
+
+
+7 3 2 0
+9 0 0 1
+",
+        )
+        .expect("parsing failed");
+
+        assert_eq!(
+            file.samples,
+            vec![
+                Sample {
+                    before: [3, 2, 1, 1],
+                    instruction: [9, 2, 1, 2],
+                    after: [3, 2, 2, 1],
+                },
+                Sample {
+                    before: [0, 1, 2, 3],
+                    instruction: [0, 1, 2, 3],
+                    after: [0, 1, 2, 3],
+                },
+            ]
+        );
+        assert_eq!(file.program, vec![[7, 3, 2, 0], [9, 0, 0, 1]]);
+        assert_eq!(parse_samples(""), Ok(SampleFile::default()));
+    }
+
+    #[test]
+    fn sample_errors() {
+        let error = |line, column, expected| {
+            Err(ParseError {
+                line,
+                column,
+                expected,
+            })
+        };
+
+        assert_eq!(
+            parse_samples("Before: [3, 2, 1]\n9 2 1 2\nAfter:  [3, 2, 2, 1]\n"),
+            error(1, 17, "a comma")
+        );
+        assert_eq!(
+            parse_samples("Before: [3, 2, 1, 1]\n9 2 1\nAfter:  [3, 2, 2, 1]\n"),
+            error(2, 6, "an integer")
+        );
+        assert_eq!(
+            parse_samples("Before: [3, 2, 1, 1]\n9 2 1 2\n\nBefore: [3, 2, 2, 1]\n"),
+            error(4, 1, "an After: line")
+        );
+        assert_eq!(
+            parse_samples("Before: [3, 2, 1, 1]\n9 2 1 2\n"),
+            error(3, 1, "an After: line")
+        );
+        assert_eq!(
+            parse_samples("1 2 3 4\nBefore: [3, 2, 1, 1]\n"),
+            error(2, 1, "an integer")
+        );
+    }
+}
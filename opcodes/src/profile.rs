@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use crate::disasm::Disassembler;
+use crate::{Fault, Machine, Register, Status};
+
+// Execution statistics gathered by run(): how often each instruction executed, the range of
+// values each register held, and how often each backwards jump was taken.
+#[derive(Clone, Debug)]
+pub struct Profile<R, const N: usize> {
+    pub hits: Vec<u64>,
+    pub ranges: [Option<(R, R)>; N],
+    pub back_edges: HashMap<(usize, usize), u64>,
+}
+
+impl<R: Register, const N: usize> Profile<R, N> {
+    pub fn new(len: usize) -> Self {
+        Profile {
+            hits: vec![0; len],
+            ranges: [None; N],
+            back_edges: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, from: usize, to: usize, registers: &[R; N]) {
+        self.hits[from] += 1;
+        if to <= from {
+            *self.back_edges.entry((from, to)).or_insert(0) += 1;
+        }
+
+        for (range, &value) in self.ranges.iter_mut().zip(registers) {
+            *range = match *range {
+                Some((low, high)) => Some((std::cmp::min(low, value), std::cmp::max(high, value))),
+                None => Some((value, value)),
+            };
+        }
+    }
+
+    // Most-taken first; ties broken by position so the output is stable.
+    pub fn hottest_back_edges(&self) -> Vec<((usize, usize), u64)> {
+        let mut edges: Vec<_> = self.back_edges.iter().map(|(&e, &n)| (e, n)).collect();
+        edges.sort_by_key(|&(edge, count)| (std::cmp::Reverse(count), edge));
+        edges
+    }
+
+    pub fn annotate(&self, disassembler: &Disassembler) -> String {
+        let width = self
+            .hits
+            .iter()
+            .max()
+            .map_or(1, |max| max.to_string().len());
+        let mut listing = String::new();
+
+        for (index, hits) in self.hits.iter().enumerate() {
+            listing.push_str(&format!(
+                "{:>width$} {:>3}: {}\n",
+                hits,
+                index,
+                disassembler.line(index),
+                width = width
+            ));
+        }
+
+        listing.push_str("\nhottest back-edges:\n");
+        for ((from, to), count) in self.hottest_back_edges().into_iter().take(5) {
+            listing.push_str(&format!("  {:>3} -> {:<3} {}\n", from, to, count));
+        }
+
+        listing.push_str("\nregister ranges:\n");
+        for (register, range) in self.ranges.iter().enumerate() {
+            if let Some((low, high)) = range {
+                listing.push_str(&format!("  r{}: {:?}..={:?}\n", register, low, high));
+            }
+        }
+
+        listing
+    }
+}
+
+// Runs the machine until it halts, or for at most `max_steps` instructions.
+pub fn run<R: Register, const N: usize>(
+    machine: &mut Machine<R, N>,
+    max_steps: Option<u64>,
+) -> Result<Profile<R, N>, Fault> {
+    let mut profile = Profile::new(machine.program().len());
+
+    for _ in 0..max_steps.unwrap_or(u64::MAX) {
+        let from = machine.ip();
+        let status = machine.step()?;
+        if from < profile.hits.len() {
+            profile.record(from, machine.ip(), &machine.registers);
+        }
+        if status == Status::Halted {
+            break;
+        }
+    }
+
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_program;
+
+    #[test]
+    fn counts() {
+        // count r1 up to 3
+        let program = parse_program(
+            "#ip 0
+seti 0 0 1
+addi 1 1 1
+gtri 1 2 2
+addr 0 2 0
+seti 0 0 0
+",
+        )
+        .unwrap();
+
+        let mut machine: Machine = Machine::from(program.clone());
+        let profile = run(&mut machine, None).unwrap();
+
+        assert_eq!(profile.hits, vec![1, 3, 3, 3, 2]);
+        assert_eq!(profile.hottest_back_edges(), vec![((4, 1), 2)]);
+        assert_eq!(profile.ranges[1], Some((0, 3)));
+        assert_eq!(profile.ranges[2], Some((0, 1)));
+        assert_eq!(profile.ranges[5], Some((0, 0)));
+
+        let listing = profile.annotate(&Disassembler::new(&program));
+        assert!(listing.starts_with("1   0: r1 = 0\n3   1: r1 = r1 + 1\n"));
+        assert!(listing.contains("    4 -> 1   2\n"));
+        assert!(listing.contains("  r1: 0..=3\n"));
+
+        let mut machine: Machine = Machine::from(program);
+        assert_eq!(
+            run(&mut machine, Some(6)).unwrap().hits,
+            vec![1, 2, 1, 1, 1]
+        );
+    }
+}
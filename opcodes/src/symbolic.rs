@@ -0,0 +1,541 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::Opcode::*;
+use crate::{Arithmetic, Error, Instruction, Op, Opcode, Operand, Program, Reg, Source};
+
+// An expression over the registers' initial values.  Constructors simplify as they go, so
+// anything that can be worked out without the inputs becomes a constant.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Expr {
+    Const(Reg),
+    // The initial value of a register.
+    Input(usize),
+    Add(Rc<Expr>, Rc<Expr>),
+    Mul(Rc<Expr>, Rc<Expr>),
+    And(Rc<Expr>, Rc<Expr>),
+    Or(Rc<Expr>, Rc<Expr>),
+    Gt(Rc<Expr>, Rc<Expr>),
+    Eq(Rc<Expr>, Rc<Expr>),
+}
+
+use self::Expr::*;
+
+fn constant(value: Reg) -> Rc<Expr> {
+    Rc::new(Const(value))
+}
+
+impl Expr {
+    pub fn as_const(&self) -> Option<Reg> {
+        match *self {
+            Const(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    // Builds `opcode a b` from already-fetched operands.  Returns None only if both are
+    // constants and the arithmetic overflows, which would fault.
+    pub fn apply(opcode: Opcode, a: Rc<Expr>, b: Rc<Expr>) -> Option<Rc<Expr>> {
+        if let (Some(x), Some(y)) = (a.as_const(), b.as_const()) {
+            return opcode.apply(Arithmetic::Checked, x, y).map(constant);
+        }
+
+        // Keep constants on the right of commutative operators, so the rules below see them.
+        let (a, b) = match opcode {
+            Addr | Addi | Mulr | Muli | Banr | Bani | Borr | Bori | Eqir | Eqri | Eqrr
+                if a.as_const().is_some() =>
+            {
+                (b, a)
+            }
+            _ => (a, b),
+        };
+
+        Some(match (opcode, &*a, b.as_const()) {
+            (Setr, ..) | (Seti, ..) => a,
+            (Addr, _, Some(0)) | (Addi, _, Some(0)) => a,
+            (Addr, Add(x, y), Some(c)) | (Addi, Add(x, y), Some(c)) => match y.as_const() {
+                Some(d) => match c.checked_add(d) {
+                    Some(sum) => Rc::new(Add(x.clone(), constant(sum))),
+                    None => Rc::new(Add(a.clone(), b)),
+                },
+                None => Rc::new(Add(a.clone(), b)),
+            },
+            (Addr, ..) | (Addi, ..) => Rc::new(Add(a, b)),
+            (Mulr, _, Some(0)) | (Muli, _, Some(0)) => constant(0),
+            (Mulr, _, Some(1)) | (Muli, _, Some(1)) => a,
+            (Mulr, ..) | (Muli, ..) => Rc::new(Mul(a, b)),
+            (Banr, _, Some(0)) | (Bani, _, Some(0)) => constant(0),
+            (Banr, ..) | (Bani, ..) if a == b => a,
+            (Banr, ..) | (Bani, ..) => Rc::new(And(a, b)),
+            (Borr, _, Some(0)) | (Bori, _, Some(0)) => a,
+            (Borr, ..) | (Bori, ..) if a == b => a,
+            (Borr, ..) | (Bori, ..) => Rc::new(Or(a, b)),
+            (Gtir, ..) | (Gtri, ..) | (Gtrr, ..) if a == b => constant(0),
+            (Gtir, ..) | (Gtri, ..) | (Gtrr, ..) => Rc::new(Gt(a, b)),
+            (Eqir, ..) | (Eqri, ..) | (Eqrr, ..) if a == b => constant(1),
+            (Eqir, ..) | (Eqri, ..) | (Eqrr, ..) => Rc::new(Eq(a, b)),
+        })
+    }
+
+    // The first comparison found in a depth-first walk, whose outcome would settle more of the
+    // expression.
+    fn comparison(self: &Rc<Self>) -> Option<Rc<Expr>> {
+        match &**self {
+            Const(_) | Input(_) => None,
+            Gt(..) | Eq(..) => Some(self.clone()),
+            Add(a, b) | Mul(a, b) | And(a, b) | Or(a, b) => {
+                a.comparison().or_else(|| b.comparison())
+            }
+        }
+    }
+
+    // Replaces every occurrence of `condition` with its assumed outcome, simplifying again.
+    // None means the assumption makes some arithmetic overflow.
+    fn assume(self: &Rc<Self>, condition: &Rc<Expr>, holds: bool) -> Option<Rc<Expr>> {
+        if self == condition {
+            return Some(constant(holds as Reg));
+        }
+
+        let rebuild = |opcode, a: &Rc<Expr>, b: &Rc<Expr>| {
+            Expr::apply(
+                opcode,
+                a.assume(condition, holds)?,
+                b.assume(condition, holds)?,
+            )
+        };
+        match &**self {
+            Const(_) | Input(_) => Some(self.clone()),
+            Add(a, b) => rebuild(Addr, a, b),
+            Mul(a, b) => rebuild(Mulr, a, b),
+            And(a, b) => rebuild(Banr, a, b),
+            Or(a, b) => rebuild(Borr, a, b),
+            Gt(a, b) => rebuild(Gtrr, a, b),
+            Eq(a, b) => rebuild(Eqrr, a, b),
+        }
+    }
+
+    // Binding strength when displayed, loosest first.
+    fn precedence(&self) -> u8 {
+        match self {
+            Gt(..) | Eq(..) => 0,
+            Or(..) => 1,
+            And(..) => 2,
+            Add(..) => 3,
+            Mul(..) => 4,
+            Const(_) | Input(_) => 5,
+        }
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (a, operator, b) = match self {
+            Const(value) => return write!(f, "{}", value),
+            Input(register) => return write!(f, "r{}", register),
+            Add(a, b) => (a, "+", b),
+            Mul(a, b) => (a, "*", b),
+            And(a, b) => (a, "&", b),
+            Or(a, b) => (a, "|", b),
+            Gt(a, b) => (a, ">", b),
+            Eq(a, b) => (a, "==", b),
+        };
+
+        // Operators group to the left, so only a right operand of equal strength needs brackets.
+        let precedence = self.precedence();
+        if a.precedence() < precedence || precedence == 0 && a.precedence() == 0 {
+            write!(f, "({})", a)?;
+        } else {
+            write!(f, "{}", a)?;
+        }
+        write!(f, " {} ", operator)?;
+        if b.precedence() <= precedence {
+            write!(f, "({})", b)
+        } else {
+            write!(f, "{}", b)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum End {
+    Halted,
+    // The path was still running after the step limit; likely a loop with a symbolic bound.
+    StepLimit { ip: usize },
+    // The instruction pointer was written with something no comparison could settle.
+    Dynamic { ip: usize, target: Rc<Expr> },
+    Fault { ip: usize, error: Error },
+}
+
+// One way through the program: the comparison outcomes it relied on, in order, and the
+// registers where it stopped.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Path<const N: usize> {
+    pub conditions: Vec<(Rc<Expr>, bool)>,
+    pub registers: [Rc<Expr>; N],
+    pub steps: u64,
+    pub end: End,
+}
+
+impl<const N: usize> std::fmt::Display for Path<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.conditions.is_empty() {
+            writeln!(f, "always:")?;
+        }
+        for (i, (condition, holds)) in self.conditions.iter().enumerate() {
+            let keyword = if i == 0 { "if" } else { "and" };
+            match holds {
+                true => writeln!(f, "{} {}", keyword, condition)?,
+                false => writeln!(f, "{} not ({})", keyword, condition)?,
+            }
+        }
+
+        match &self.end {
+            End::Halted => writeln!(f, "  halts after {} steps", self.steps)?,
+            End::StepLimit { ip } => {
+                writeln!(f, "  still running at {} after {} steps", ip, self.steps)?
+            }
+            End::Dynamic { ip, target } => {
+                writeln!(f, "  instruction {} sets the ip to {}", ip, target)?
+            }
+            End::Fault { ip, error } => writeln!(f, "  instruction {} faults: {}", ip, error)?,
+        }
+        for (register, value) in self.registers.iter().enumerate() {
+            writeln!(f, "  r{} = {}", register, value)?;
+        }
+        Ok(())
+    }
+}
+
+// Runs a program on expressions instead of numbers, following both outcomes of any comparison
+// that decides where the instruction pointer goes.
+#[derive(Clone, Debug)]
+pub struct Explorer<const N: usize = 6> {
+    // What each register starts as: by default its own symbolic input.
+    pub initial: [Rc<Expr>; N],
+    // Per path, since loops with a symbolic bound never finish.
+    pub max_steps: u64,
+    pub max_paths: usize,
+}
+
+impl<const N: usize> Default for Explorer<N> {
+    fn default() -> Self {
+        Explorer {
+            initial: std::array::from_fn(|register| Rc::new(Input(register))),
+            max_steps: 10_000,
+            max_paths: 64,
+        }
+    }
+}
+
+// A path still being explored.  Once `end` is set it's finished, and `ip` no longer matters.
+#[derive(Clone)]
+struct State<const N: usize> {
+    ip: usize,
+    path: Path<N>,
+    end: Option<End>,
+}
+
+impl<const N: usize> Explorer<N> {
+    // Every path found, in the order they finished.  Stops once max_paths have finished, so the
+    // result may not cover every input.
+    pub fn explore(&self, program: &Program) -> Vec<Path<N>> {
+        let mut finished = Vec::new();
+        let mut pending = VecDeque::new();
+        pending.push_back(State {
+            ip: 0,
+            path: Path {
+                conditions: vec![],
+                registers: self.initial.clone(),
+                steps: 0,
+                end: End::Halted,
+            },
+            end: None,
+        });
+
+        while let Some(mut state) = pending.pop_front() {
+            if finished.len() >= self.max_paths {
+                break;
+            }
+
+            while state.end.is_none() {
+                match program.instructions.get(state.ip) {
+                    None => state.end = Some(End::Halted),
+                    Some(_) if state.path.steps >= self.max_steps => {
+                        state.end = Some(End::StepLimit { ip: state.ip })
+                    }
+                    Some(&instruction) => {
+                        let mut next = self.step(program, state, instruction).into_iter();
+                        state = next
+                            .next()
+                            .expect("a step always continues at least one path");
+                        pending.extend(next);
+                    }
+                }
+            }
+
+            state.path.end = state.end.expect("the loop only exits on an end");
+            finished.push(state.path);
+        }
+
+        finished
+    }
+
+    // Executes one instruction.  If it writes the instruction pointer with something that
+    // depends on comparisons, the path splits into one per outcome.
+    fn step(
+        &self,
+        program: &Program,
+        mut state: State<N>,
+        instruction: Instruction,
+    ) -> Vec<State<N>> {
+        let ip = state.ip;
+        let Instruction(opcode, _, _, c) = instruction;
+        if let Some(binding) = program.ip_binding {
+            state.path.registers[binding] = constant(ip as Reg);
+        }
+
+        match self.execute(&state.path.registers, instruction) {
+            Ok(value) => state.path.registers[c] = value,
+            Err(error) => {
+                state.end = Some(End::Fault { ip, error });
+                return vec![state];
+            }
+        }
+        state.path.steps += 1;
+
+        match program.ip_binding {
+            Some(binding) => self.resolve(state, binding, opcode),
+            None => {
+                state.ip += 1;
+                vec![state]
+            }
+        }
+    }
+
+    fn execute(
+        &self,
+        registers: &[Rc<Expr>; N],
+        instruction: Instruction,
+    ) -> Result<Rc<Expr>, Error> {
+        let Instruction(opcode, a, b, c) = instruction;
+        let out_of_range = |operand, index| Error::RegisterOutOfRange {
+            opcode,
+            operand,
+            index,
+            width: N,
+        };
+        let fetch = |source, operand, index: Op| match source {
+            Source::Register => registers
+                .get(index)
+                .cloned()
+                .ok_or_else(|| out_of_range(operand, index)),
+            Source::Immediate => {
+                Reg::try_from(index)
+                    .map(constant)
+                    .map_err(|_| Error::ImmediateOutOfRange {
+                        opcode,
+                        operand,
+                        value: index,
+                    })
+            }
+            Source::Ignored => Ok(constant(0)),
+        };
+
+        let (source_a, source_b) = opcode.sources();
+        let a = fetch(source_a, Operand::A, a)?;
+        let b = fetch(source_b, Operand::B, b)?;
+        if c >= N {
+            return Err(out_of_range(Operand::C, c));
+        }
+        Expr::apply(opcode, a, b).ok_or(Error::Overflow { opcode })
+    }
+
+    // Settles the value just written to the bound register, splitting the path on each
+    // comparison it depends on, and moves every resulting path to its next instruction.
+    fn resolve(&self, mut state: State<N>, binding: usize, opcode: Opcode) -> Vec<State<N>> {
+        let ip = state.ip;
+        let target = state.path.registers[binding].clone();
+        if let Some(target) = target.as_const() {
+            state.ip = usize::try_from(target)
+                .ok()
+                .and_then(|target| target.checked_add(1))
+                .unwrap_or(usize::MAX);
+            return vec![state];
+        }
+
+        let condition = match target.comparison() {
+            Some(condition) => condition,
+            None => {
+                state.end = Some(End::Dynamic { ip, target });
+                return vec![state];
+            }
+        };
+
+        let known = state
+            .path
+            .conditions
+            .iter()
+            .find(|(known, _)| *known == condition)
+            .map(|&(_, holds)| holds);
+        let outcomes = match known {
+            Some(holds) => vec![holds],
+            None => vec![true, false],
+        };
+
+        outcomes
+            .into_iter()
+            .flat_map(|holds| {
+                let mut state = state.clone();
+                if known.is_none() {
+                    state.path.conditions.push((condition.clone(), holds));
+                }
+                let assumed: Option<Vec<Rc<Expr>>> = state
+                    .path
+                    .registers
+                    .iter()
+                    .map(|register| register.assume(&condition, holds))
+                    .collect();
+                match assumed {
+                    Some(registers) => {
+                        state.path.registers.clone_from_slice(&registers);
+                        self.resolve(state, binding, opcode)
+                    }
+                    None => {
+                        state.end = Some(End::Fault {
+                            ip,
+                            error: Error::Overflow { opcode },
+                        });
+                        vec![state]
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn simplify() {
+        let r0 = || Rc::new(Input(0));
+        let sum = Expr::apply(Addi, r0(), constant(2)).unwrap();
+        let sum = Expr::apply(Addr, constant(3), sum).unwrap();
+        assert_eq!(sum.to_string(), "r0 + 5");
+        assert_eq!(
+            Expr::apply(Muli, sum.clone(), constant(1)),
+            Some(sum.clone())
+        );
+        assert_eq!(
+            Expr::apply(Mulr, sum.clone(), constant(0)),
+            Some(constant(0))
+        );
+        assert_eq!(
+            Expr::apply(Eqrr, sum.clone(), sum.clone()),
+            Some(constant(1))
+        );
+        assert_eq!(Expr::apply(Addi, constant(Reg::MAX), constant(1)), None);
+
+        let product = Expr::apply(Mulr, sum.clone(), Rc::new(Input(1))).unwrap();
+        assert_eq!(product.to_string(), "(r0 + 5) * r1");
+        let flag = Expr::apply(Gtrr, product.clone(), r0()).unwrap();
+        assert_eq!(flag.to_string(), "(r0 + 5) * r1 > r0");
+        assert_eq!(flag.assume(&flag, true), Some(constant(1)));
+    }
+
+    #[test]
+    fn paths() {
+        let program = crate::parse_program(
+            "#ip 5
+gtri 0 5 1
+addr 1 5 5
+seti 7 0 5
+muli 0 3 2
+addi 2 1 2
+",
+        )
+        .unwrap();
+        let paths = Explorer::<6>::default().explore(&program);
+        assert_eq!(paths.len(), 2);
+
+        assert_eq!(
+            paths[0].to_string(),
+            "if r0 > 5
+  halts after 4 steps
+  r0 = r0
+  r1 = 1
+  r2 = r0 * 3 + 1
+  r3 = r3
+  r4 = r4
+  r5 = 4
+"
+        );
+        assert_eq!(
+            paths[1].to_string(),
+            "if not (r0 > 5)
+  halts after 3 steps
+  r0 = r0
+  r1 = 0
+  r2 = r2
+  r3 = r3
+  r4 = r4
+  r5 = 7
+"
+        );
+    }
+
+    #[test]
+    fn limits() {
+        // Counts r1 up to r0, which never settles when r0 is unknown.
+        let program = crate::parse_program(
+            "#ip 5
+seti 0 0 1
+addi 1 1 1
+gtrr 1 0 2
+addr 2 5 5
+seti 0 0 5
+",
+        )
+        .unwrap();
+        let explorer: Explorer = Explorer {
+            max_steps: 100,
+            max_paths: 4,
+            ..Explorer::default()
+        };
+        let paths = explorer.explore(&program);
+        assert_eq!(paths.len(), 4);
+        assert_eq!(paths[0].conditions.len(), 1);
+        assert_eq!(paths[0].end, End::Halted);
+        assert_eq!(paths[0].registers[1], constant(1));
+        assert_eq!(paths[3].conditions.len(), 4);
+
+        // With r0 known, there's only one way through.
+        let explorer = Explorer {
+            initial: [
+                constant(3),
+                constant(0),
+                constant(0),
+                constant(0),
+                constant(0),
+                constant(0),
+            ],
+            ..explorer
+        };
+        let paths = explorer.explore(&program);
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].conditions.is_empty());
+        assert_eq!(paths[0].registers[1], constant(4));
+
+        let program = crate::parse_program("#ip 1\naddr 0 1 1\n").unwrap();
+        let paths = Explorer::<6>::default().explore(&program);
+        assert_eq!(
+            paths[0].end,
+            End::Dynamic {
+                ip: 0,
+                target: Rc::new(Input(0)),
+            }
+        );
+    }
+}
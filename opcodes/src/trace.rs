@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::io::Write;
+
+use crate::{Fault, Machine, Reg, Register, Status};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Change<R> {
+    pub register: usize,
+    pub before: R,
+    pub after: R,
+}
+
+// Runs a machine while remembering which registers each step changed, so that it can be run
+// backwards again.  Only the most recent `limit` steps are kept, if there is a limit.
+pub struct Tracer<R = Reg, const N: usize = 6> {
+    pub machine: Machine<R, N>,
+    limit: Option<usize>,
+    // the registers before the oldest step we still remember
+    start: [R; N],
+    // (instruction pointer, number of changes) for each step
+    steps: VecDeque<(usize, usize)>,
+    changes: VecDeque<Change<R>>,
+}
+
+impl<R: Register, const N: usize> Tracer<R, N> {
+    pub fn new(machine: Machine<R, N>, limit: Option<usize>) -> Self {
+        Tracer {
+            start: machine.registers,
+            machine,
+            limit,
+            steps: VecDeque::new(),
+            changes: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    // Forgets all history, e.g. because the registers were changed by hand and the trace no
+    // longer explains how the machine got to where it is.
+    pub fn clear(&mut self) {
+        self.start = self.machine.registers;
+        self.steps.clear();
+        self.changes.clear();
+    }
+
+    pub fn step(&mut self) -> Result<Status, Fault> {
+        let ip = self.machine.ip();
+        let before = self.machine.registers;
+        let steps = self.machine.steps();
+
+        let status = self.machine.step()?;
+        if self.machine.steps() == steps {
+            return Ok(status);
+        }
+
+        let mut count = 0;
+        for (register, (&before, &after)) in before.iter().zip(&self.machine.registers).enumerate()
+        {
+            if before != after {
+                self.changes.push_back(Change {
+                    register,
+                    before,
+                    after,
+                });
+                count += 1;
+            }
+        }
+        self.steps.push_back((ip, count));
+
+        if matches!(self.limit, Some(limit) if self.steps.len() > limit) {
+            let (_, count) = self.steps.pop_front().expect("just pushed");
+            for change in self.changes.drain(..count) {
+                self.start[change.register] = change.after;
+            }
+        }
+
+        Ok(status)
+    }
+
+    pub fn run_for(&mut self, steps: u64) -> Result<Status, Fault> {
+        for _ in 0..steps {
+            if self.step()? == Status::Halted {
+                return Ok(Status::Halted);
+            }
+        }
+        Ok(self.machine.status())
+    }
+
+    // Undoes the most recent step, returning the changes it had made.
+    pub fn step_back(&mut self) -> Option<Vec<Change<R>>> {
+        let (ip, count) = self.steps.pop_back()?;
+        let undone: Vec<_> = self.changes.drain(self.changes.len() - count..).collect();
+        for change in &undone {
+            self.machine.registers[change.register] = change.before;
+        }
+        self.machine.rewind(ip);
+        Some(undone)
+    }
+
+    // Steps backwards until just before the most recent instruction that changed `register`, and
+    // returns that change.  If no remembered step changed it, rewinds nothing.
+    pub fn back_to_change(&mut self, register: usize) -> Option<Change<R>> {
+        self.changes.iter().rev().find(|c| c.register == register)?;
+        loop {
+            let undone = self.step_back().expect("a change was found above");
+            if let Some(&change) = undone.iter().find(|c| c.register == register) {
+                return Some(change);
+            }
+        }
+    }
+
+    // Calls `f` with (step number, instruction pointer, registers afterwards) for every
+    // remembered step, oldest first.
+    fn replay<F>(&self, mut f: F) -> std::io::Result<()>
+    where
+        F: FnMut(u64, usize, &[R; N]) -> std::io::Result<()>,
+    {
+        let mut registers = self.start;
+        let mut changes = self.changes.iter();
+        let first = self.machine.steps() - self.steps.len() as u64;
+
+        for (i, &(ip, count)) in self.steps.iter().enumerate() {
+            for change in changes.by_ref().take(count) {
+                registers[change.register] = change.after;
+            }
+            f(first + i as u64 + 1, ip, &registers)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_csv<W: Write>(&self, mut out: W) -> std::io::Result<()> {
+        write!(out, "step,ip")?;
+        for register in 0..N {
+            write!(out, ",r{}", register)?;
+        }
+        writeln!(out)?;
+
+        self.replay(|step, ip, registers| {
+            write!(out, "{},{}", step, ip)?;
+            for value in registers {
+                write!(out, ",{:?}", value)?;
+            }
+            writeln!(out)
+        })
+    }
+
+    pub fn write_jsonl<W: Write>(&self, mut out: W) -> std::io::Result<()> {
+        self.replay(|step, ip, registers| {
+            let registers: Vec<String> = registers.iter().map(|r| format!("{:?}", r)).collect();
+            writeln!(
+                out,
+                "{{\"step\":{},\"ip\":{},\"registers\":[{}]}}",
+                step,
+                ip,
+                registers.join(",")
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_program;
+
+    fn counter() -> Machine {
+        Machine::from(
+            parse_program(
+                "#ip 0
+seti 0 0 1
+addi 1 1 1
+gtri 1 2 2
+addr 0 2 0
+seti 0 0 0
+",
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn backwards() {
+        let mut tracer = Tracer::new(counter(), None);
+        assert_eq!(tracer.run_for(100), Ok(Status::Halted));
+        let halted = tracer.machine.registers;
+        assert_eq!(tracer.len(), 12);
+
+        let change = tracer.back_to_change(1).unwrap();
+        assert_eq!(
+            change,
+            Change {
+                register: 1,
+                before: 2,
+                after: 3
+            }
+        );
+        assert_eq!(tracer.machine.ip(), 1);
+        assert_eq!(tracer.machine.registers[1], 2);
+        assert_eq!(tracer.machine.steps(), 9);
+
+        // replaying forwards ends up in the same place
+        tracer.run_for(100).unwrap();
+        assert_eq!(tracer.machine.registers, halted);
+
+        while tracer.step_back().is_some() {}
+        assert_eq!(tracer.machine.registers, [0; 6]);
+        assert_eq!(tracer.machine.ip(), 0);
+        assert_eq!(tracer.machine.steps(), 0);
+        assert_eq!(tracer.back_to_change(1), None);
+    }
+
+    #[test]
+    fn export() {
+        let mut tracer = Tracer::new(counter(), Some(3));
+        tracer.run_for(5).unwrap();
+        assert_eq!(tracer.len(), 3);
+
+        let mut csv = Vec::new();
+        tracer.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "step,ip,r0,r1,r2,r3,r4,r5
+3,2,2,1,0,0,0,0
+4,3,3,1,0,0,0,0
+5,4,0,1,0,0,0,0
+"
+        );
+
+        let mut jsonl = Vec::new();
+        tracer.write_jsonl(&mut jsonl).unwrap();
+        assert_eq!(
+            String::from_utf8(jsonl).unwrap().lines().next(),
+            Some("{\"step\":3,\"ip\":2,\"registers\":[2,1,0,0,0,0]}")
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use std::fmt::Write;
+
+use crate::flow::{Exit, Graph};
+use crate::Opcode::*;
+use crate::{Error, Fault, Instruction, Operand, Program, Source};
+
+// Programs are translated for the usual six 64-bit registers.
+const REGISTERS: usize = 6;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Style {
+    // One `match ip` arm per instruction.  Always works, but dispatches on every step.
+    PerInstruction,
+    // One arm per basic block of the control-flow graph, with straight-line code inside.  A
+    // computed jump could land anywhere, so a program with one falls back to PerInstruction.
+    Blocks,
+}
+
+// Everything the generated source needs besides the `match` arms themselves.
+const PRELUDE: &str = "\
+#[allow(dead_code)]
+fn overflow(ip: usize) -> ! {
+    panic!(\"instruction {} overflowed\", ip)
+}
+
+#[allow(dead_code)]
+fn next(value: u64) -> usize {
+    use std::convert::TryFrom;
+    usize::try_from(value).map_or(usize::MAX, |ip| ip.saturating_add(1))
+}
+";
+
+const MAIN: &str = "
+#[allow(dead_code)]
+fn main() {
+    let mut registers = [0; 6];
+    if let Some(r0) = std::env::args().nth(1) {
+        registers[0] = r0.parse().expect(\"r0 must be an integer\");
+    }
+    let steps = run(&mut registers);
+    println!(\"{:?} after {} steps\", registers, steps);
+}
+";
+
+fn check(index: usize, instruction: Instruction) -> Result<(), Fault> {
+    let Instruction(opcode, a, b, c) = instruction;
+    let (source_a, source_b) = opcode.sources();
+
+    for &(source, operand, value) in &[
+        (source_a, Operand::A, a),
+        (source_b, Operand::B, b),
+        (Source::Register, Operand::C, c),
+    ] {
+        if source == Source::Register && value >= REGISTERS {
+            return Err(Fault {
+                ip: index,
+                error: Error::RegisterOutOfRange {
+                    opcode,
+                    operand,
+                    index: value,
+                    width: REGISTERS,
+                },
+            });
+        }
+    }
+    Ok(())
+}
+
+// The assignment performed by one instruction.  Reads of the instruction pointer's register
+// become the instruction's own index, since that's what the register holds at that point.
+fn statement(ip_binding: Option<usize>, index: usize, instruction: Instruction) -> String {
+    let Instruction(opcode, a, b, c) = instruction;
+    let (source_a, source_b) = opcode.sources();
+    let operand = |source, value| match source {
+        Source::Register if Some(value) == ip_binding => index.to_string(),
+        Source::Register => format!("r[{}]", value),
+        Source::Immediate | Source::Ignored => value.to_string(),
+    };
+    let (a, b) = (operand(source_a, a), operand(source_b, b));
+
+    let value = match opcode {
+        Addr | Addi => format!(
+            "u64::checked_add({}, {}).unwrap_or_else(|| overflow({}))",
+            a, b, index
+        ),
+        Mulr | Muli => format!(
+            "u64::checked_mul({}, {}).unwrap_or_else(|| overflow({}))",
+            a, b, index
+        ),
+        Banr | Bani => format!("{} & {}", a, b),
+        Borr | Bori => format!("{} | {}", a, b),
+        Setr | Seti => a,
+        Gtir | Gtri | Gtrr => format!("({} > {}) as u64", a, b),
+        Eqir | Eqri | Eqrr => format!("({} == {}) as u64", a, b),
+    };
+    format!("r[{}] = {};", c, value)
+}
+
+// Renders a program as Rust source defining `pub fn run(r: &mut [u64; 6]) -> u64`, which runs it
+// to completion and returns the number of instructions executed, plus a `main` that takes r0 as
+// its only argument.  The result compiles on its own with `rustc -O`, or can be included as a
+// module.  Overflow panics, as `Arithmetic::Checked` would fault.
+pub fn transpile(program: &Program, style: Style) -> Result<String, Fault> {
+    for (index, &instruction) in program.instructions.iter().enumerate() {
+        check(index, instruction)?;
+    }
+    if let Some(binding) = program.ip_binding {
+        if binding >= REGISTERS {
+            return Err(Fault {
+                ip: 0,
+                error: Error::RegisterOutOfRange {
+                    opcode: Setr,
+                    operand: Operand::C,
+                    index: binding,
+                    width: REGISTERS,
+                },
+            });
+        }
+    }
+
+    let len = program.instructions.len();
+    let per_instruction = || (0..len).map(|i| (i, i + 1, Exit::Next(i + 1))).collect();
+    let blocks: Vec<(usize, usize, Exit)> = match style {
+        Style::PerInstruction => per_instruction(),
+        Style::Blocks => {
+            let graph = Graph::new(program);
+            if graph
+                .blocks()
+                .iter()
+                .any(|block| block.exit == Exit::Dynamic)
+            {
+                per_instruction()
+            } else {
+                graph
+                    .blocks()
+                    .iter()
+                    .map(|block| (block.start, block.end, block.exit))
+                    .collect()
+            }
+        }
+    };
+
+    let mut source = String::new();
+    writeln!(
+        source,
+        "// Translated from elfcode: {} instructions, ip {}.\n",
+        len,
+        program
+            .ip_binding
+            .map_or("unbound".to_string(), |ip| format!("bound to r{}", ip))
+    )
+    .unwrap();
+    source.push_str(PRELUDE);
+    source.push_str("\npub fn run(r: &mut [u64; 6]) -> u64 {\n");
+    source.push_str("    let mut ip: usize = 0;\n");
+    source.push_str("    let mut steps: u64 = 0;\n");
+    source.push_str("    loop {\n");
+    source.push_str("        match ip {\n");
+
+    for (start, end, exit) in blocks {
+        writeln!(source, "            {} => {{", start).unwrap();
+        for index in start..end {
+            let instruction = program.instructions[index];
+            writeln!(
+                source,
+                "                // {}\n                {}",
+                instruction,
+                statement(program.ip_binding, index, instruction)
+            )
+            .unwrap();
+        }
+        writeln!(source, "                steps += {};", end - start).unwrap();
+
+        // Only the last instruction of a block can write the instruction pointer's register.
+        let Instruction(_, _, _, c) = program.instructions[end - 1];
+        match (program.ip_binding, exit) {
+            (Some(ip), Exit::Jump(target)) if ip == c => {
+                writeln!(source, "                ip = {};", target).unwrap()
+            }
+            (Some(ip), _) if ip == c => {
+                writeln!(source, "                ip = next(r[{}]);", ip).unwrap()
+            }
+            (Some(ip), _) => writeln!(
+                source,
+                "                r[{}] = {};\n                ip = {};",
+                ip,
+                end - 1,
+                end
+            )
+            .unwrap(),
+            (None, _) => writeln!(source, "                ip = {};", end).unwrap(),
+        }
+        source.push_str("            }\n");
+    }
+
+    source.push_str("            _ => return steps,\n");
+    source.push_str("        }\n");
+    source.push_str("    }\n");
+    source.push_str("}\n");
+    source.push_str(MAIN);
+    Ok(source)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn arms() {
+        let program = crate::parse_program(
+            "#ip 1
+seti 5 0 2
+gtrr 2 0 3
+addr 3 1 1
+seti 0 0 1
+",
+        )
+        .unwrap();
+
+        let per_instruction = transpile(&program, Style::PerInstruction).unwrap();
+        assert!(per_instruction.contains(
+            "            0 => {
+                // seti 5 0 2
+                r[2] = 5;
+                steps += 1;
+                r[1] = 0;
+                ip = 1;
+            }
+"
+        ));
+        assert!(per_instruction.contains(
+            "            2 => {
+                // addr 3 1 1
+                r[1] = u64::checked_add(r[3], 2).unwrap_or_else(|| overflow(2));
+                steps += 1;
+                ip = next(r[1]);
+            }
+"
+        ));
+
+        let blocks = transpile(&program, Style::Blocks).unwrap();
+        assert!(blocks.contains(
+            "            0 => {
+                // seti 5 0 2
+                r[2] = 5;
+                steps += 1;
+                r[1] = 0;
+                ip = 1;
+            }
+            1 => {
+                // gtrr 2 0 3
+                r[3] = (r[2] > r[0]) as u64;
+                // addr 3 1 1
+                r[1] = u64::checked_add(r[3], 2).unwrap_or_else(|| overflow(2));
+                steps += 2;
+                ip = next(r[1]);
+            }
+            3 => {
+                // seti 0 0 1
+                r[1] = 0;
+                steps += 1;
+                ip = 1;
+            }
+            _ => return steps,
+"
+        ));
+    }
+
+    #[test]
+    fn computed_jump() {
+        // addr 0 1 0 jumps to 3, which is in the middle of a block as far as the static targets
+        // go.
+        let program = crate::parse_program(
+            "#ip 0
+seti 2 0 1
+addr 0 1 0
+seti 7 0 2
+seti 8 0 3
+seti 9 0 4
+",
+        )
+        .unwrap();
+
+        let blocks = transpile(&program, Style::Blocks).unwrap();
+        assert_eq!(blocks, transpile(&program, Style::PerInstruction).unwrap());
+        assert!(blocks.contains(
+            "            3 => {
+                // seti 8 0 3
+                r[3] = 8;
+                steps += 1;
+                r[0] = 3;
+                ip = 4;
+            }
+"
+        ));
+    }
+
+    #[test]
+    fn out_of_range() {
+        let program = crate::parse_program("seti 1 0 6\n").unwrap();
+        assert_eq!(
+            transpile(&program, Style::Blocks),
+            Err(Fault {
+                ip: 0,
+                error: Error::RegisterOutOfRange {
+                    opcode: Seti,
+                    operand: Operand::C,
+                    index: 6,
+                    width: 6,
+                },
+            })
+        );
+    }
+}